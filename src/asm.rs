@@ -0,0 +1,470 @@
+use std::collections::HashMap;
+
+use crate::ops::*;
+use crate::ops_encode::EncodeError;
+
+#[derive(Debug)]
+pub enum AsmErrorKind {
+    MissingOrig,
+    UnexpectedOrig,
+    UnknownOpcode(String),
+    InvalidNumber(String),
+    InvalidRegister(String),
+    InvalidString,
+    UnterminatedString,
+    OperandCount { expected: usize, actual: usize },
+    OutOfRange { value: i32, bits: u32 },
+    DuplicateLabel(String),
+    UndefinedLabel(String),
+    AddressOverflow,
+    Encode(EncodeError),
+}
+
+#[derive(Debug)]
+pub struct AsmError {
+    pub line: usize,
+    pub kind: AsmErrorKind,
+}
+
+/// assembled image in the loader format (origin followed by the words) with its symbol table
+pub struct Program {
+    pub obj:     Vec<u16>,
+    pub symbols: Vec<(String, u16)>,
+}
+
+#[derive(Clone)]
+enum Token {
+    Word(String),
+    Str(Vec<u8>),
+}
+
+struct Line {
+    number:   usize,
+    address:  u16,
+    mnemonic: String,
+    operands: Vec<Token>,
+}
+
+fn error(line: usize, kind: AsmErrorKind) -> AsmError {
+    AsmError { line, kind }
+}
+
+fn tokenize(text: &str, line: usize) -> Result<Vec<Token>, AsmError> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ';' => break,
+            '"' => {
+                let mut value = Vec::new();
+                loop {
+                    match chars.next() {
+                        None => return Err(error(line, AsmErrorKind::UnterminatedString)),
+                        Some('"') => break,
+                        Some('\\') => value.push(match chars.next() {
+                            Some('n') => b'\n',
+                            Some('t') => b'\t',
+                            Some('r') => b'\r',
+                            Some('e') => 0x1b,
+                            Some('0') => 0,
+                            Some('"') => b'"',
+                            Some('\\') => b'\\',
+                            _ => return Err(error(line, AsmErrorKind::InvalidString)),
+                        }),
+                        Some(c) if c.is_ascii() => value.push(c as u8),
+                        Some(_) => return Err(error(line, AsmErrorKind::InvalidString)),
+                    }
+                }
+                tokens.push(Token::Str(value));
+            }
+            c if c.is_whitespace() || c == ',' => {
+                if !word.is_empty() {
+                    tokens.push(Token::Word(std::mem::take(&mut word)));
+                }
+            }
+            c => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(Token::Word(word));
+    }
+    Ok(tokens)
+}
+
+fn is_mnemonic(word: &str) -> bool {
+    let upper = word.to_ascii_uppercase();
+    if let Some(flags) = upper.strip_prefix("BR") {
+        return ["", "N", "Z", "P", "NZ", "NP", "ZP", "NZP"].contains(&flags);
+    }
+    matches!(
+        upper.as_str(),
+        "ADD" | "AND" | "JMP" | "RET" | "JSR" | "JSRR" | "LD" | "LDI" | "LDR" | "LEA" | "NOT" | "RTI" | "ST" | "STI" | "STR" | "TRAP" | "GETC" | "OUT" | "PUTS" | "IN" | "PUTSP" | "HALT" | ".ORIG" | ".FILL" | ".BLKW" | ".STRINGZ" | ".END"
+    )
+}
+
+/// parses LC-3 literals: `#10`, `#-3`, `x3000`, `0x3000`, `b101` or plain decimal
+pub fn parse_number(text: &str) -> Option<i32> {
+    let (radix, digits) = match text.as_bytes().first()? {
+        b'#' => (10, &text[1..]),
+        b'x' | b'X' => (16, &text[1..]),
+        b'b' | b'B' => (2, &text[1..]),
+        b'0' if text.len() > 2 && (text.as_bytes()[1] == b'x' || text.as_bytes()[1] == b'X') => (16, &text[2..]),
+        _ => (10, text),
+    };
+    let (negative, digits) = match digits.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, digits),
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    let value = i32::from_str_radix(digits, radix).ok()?;
+    Some(if negative { -value } else { value })
+}
+
+fn word(token: &Token, line: usize) -> Result<&str, AsmError> {
+    match token {
+        Token::Word(w) => Ok(w),
+        Token::Str(_) => Err(error(line, AsmErrorKind::InvalidString)),
+    }
+}
+
+fn register(token: &Token, line: usize) -> Result<Register, AsmError> {
+    let w = word(token, line)?;
+    match w.as_bytes() {
+        [b'R' | b'r', d @ b'0'..=b'7'] => Ok(Register((d - b'0') as usize)),
+        _ => Err(error(line, AsmErrorKind::InvalidRegister(w.to_string()))),
+    }
+}
+
+fn fit(value: i32, bits: u32, signed: bool, line: usize) -> Result<u16, AsmError> {
+    let (min, max) = if signed { (-(1 << (bits - 1)), (1 << (bits - 1)) - 1) } else { (0, (1 << bits) - 1) };
+    if value < min || value > max {
+        return Err(error(line, AsmErrorKind::OutOfRange { value, bits }));
+    }
+    Ok(value as u16)
+}
+
+fn immediate(token: &Token, bits: u32, line: usize) -> Result<u16, AsmError> {
+    let w = word(token, line)?;
+    let value = parse_number(w).ok_or_else(|| error(line, AsmErrorKind::InvalidNumber(w.to_string())))?;
+    fit(value, bits, true, line)
+}
+
+fn pc_offset(token: &Token, bits: u32, address: u16, symbols: &HashMap<String, u16>, line: usize) -> Result<u16, AsmError> {
+    let w = word(token, line)?;
+    let value = match symbols.get(w) {
+        Some(&target) => target as i32 - (address as i32 + 1),
+        None => parse_number(w).ok_or_else(|| error(line, AsmErrorKind::UndefinedLabel(w.to_string())))?,
+    };
+    fit(value, bits, true, line)
+}
+
+fn expect(operands: &[Token], expected: usize, line: usize) -> Result<(), AsmError> {
+    if operands.len() != expected {
+        return Err(error(line, AsmErrorKind::OperandCount { expected, actual: operands.len() }));
+    }
+    Ok(())
+}
+
+fn blkw_size(operands: &[Token], line: usize) -> Result<u16, AsmError> {
+    expect(operands, 1, line)?;
+    let w = word(&operands[0], line)?;
+    let value = parse_number(w).ok_or_else(|| error(line, AsmErrorKind::InvalidNumber(w.to_string())))?;
+    fit(value, 16, false, line)
+}
+
+fn stringz(operands: &[Token], line: usize) -> Result<&[u8], AsmError> {
+    expect(operands, 1, line)?;
+    match &operands[0] {
+        Token::Str(value) => Ok(value),
+        Token::Word(_) => Err(error(line, AsmErrorKind::InvalidString)),
+    }
+}
+
+fn operation(line: &Line, symbols: &HashMap<String, u16>) -> Result<Operation, AsmError> {
+    let (n, a, ops) = (line.number, line.address, &line.operands[..]);
+    let mnemonic = line.mnemonic.to_ascii_uppercase();
+    let trap = |trap_vector: u16| -> Result<Operation, AsmError> {
+        expect(ops, 0, n)?;
+        Ok(Operation::Trap { trap_vector })
+    };
+    let argument = |token: &Token| -> Result<Argument, AsmError> {
+        match register(token, n) {
+            Ok(r) => Ok(Argument::Register(r)),
+            Err(_) => Ok(Argument::Immediate(immediate(token, 5, n)?)),
+        }
+    };
+    match mnemonic.as_str() {
+        "ADD" | "AND" => {
+            expect(ops, 3, n)?;
+            let (dr, sr1, arg) = (register(&ops[0], n)?, register(&ops[1], n)?, argument(&ops[2])?);
+            Ok(if mnemonic == "ADD" { Operation::Add { dr, sr1, arg } } else { Operation::And { dr, sr1, arg } })
+        }
+        "JMP" => {
+            expect(ops, 1, n)?;
+            Ok(Operation::Jmp { base_r: register(&ops[0], n)? })
+        }
+        "RET" => {
+            expect(ops, 0, n)?;
+            Ok(Operation::Jmp { base_r: Register(7) })
+        }
+        "JSR" => {
+            expect(ops, 1, n)?;
+            Ok(Operation::Jsr { pc_offset: pc_offset(&ops[0], 11, a, symbols, n)? })
+        }
+        "JSRR" => {
+            expect(ops, 1, n)?;
+            Ok(Operation::Jsrr { base_r: register(&ops[0], n)? })
+        }
+        "LD" | "LDI" | "LEA" | "ST" | "STI" => {
+            expect(ops, 2, n)?;
+            let (r, pc_offset) = (register(&ops[0], n)?, pc_offset(&ops[1], 9, a, symbols, n)?);
+            Ok(match mnemonic.as_str() {
+                "LD" => Operation::Ld { dr: r, pc_offset },
+                "LDI" => Operation::Ldi { dr: r, pc_offset },
+                "LEA" => Operation::Lea { dr: r, pc_offset },
+                "ST" => Operation::St { sr: r, pc_offset },
+                _ => Operation::Sti { sr: r, pc_offset },
+            })
+        }
+        "LDR" | "STR" => {
+            expect(ops, 3, n)?;
+            let (r, base_r, offset) = (register(&ops[0], n)?, register(&ops[1], n)?, immediate(&ops[2], 6, n)?);
+            Ok(if mnemonic == "LDR" { Operation::Ldr { dr: r, base_r, offset } } else { Operation::Str { sr: r, base_r, offset } })
+        }
+        "NOT" => {
+            expect(ops, 2, n)?;
+            Ok(Operation::Not { dr: register(&ops[0], n)?, sr: register(&ops[1], n)? })
+        }
+        "RTI" => {
+            expect(ops, 0, n)?;
+            Ok(Operation::Rti)
+        }
+        "TRAP" => {
+            expect(ops, 1, n)?;
+            let w = word(&ops[0], n)?;
+            let value = parse_number(w).ok_or_else(|| error(n, AsmErrorKind::InvalidNumber(w.to_string())))?;
+            Ok(Operation::Trap { trap_vector: fit(value, 8, false, n)? })
+        }
+        "GETC" => trap(0x20),
+        "OUT" => trap(0x21),
+        "PUTS" => trap(0x22),
+        "IN" => trap(0x23),
+        "PUTSP" => trap(0x24),
+        "HALT" => trap(0x25),
+        br => {
+            let flags = br.strip_prefix("BR").ok_or_else(|| error(n, AsmErrorKind::UnknownOpcode(line.mnemonic.clone())))?;
+            expect(ops, 1, n)?;
+            let pc_offset = pc_offset(&ops[0], 9, a, symbols, n)?;
+            if flags.is_empty() {
+                return Ok(Operation::Br { n: true, z: true, p: true, pc_offset });
+            }
+            Ok(Operation::Br { n: flags.contains('N'), z: flags.contains('Z'), p: flags.contains('P'), pc_offset })
+        }
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Program, AsmError> {
+    // first pass: assign addresses to labels and statements
+    let mut origin = None;
+    let mut address = 0u32;
+    let mut lines = Vec::new();
+    let mut symbols = HashMap::new();
+    let mut symbol_order = Vec::new();
+    for (i, text) in source.lines().enumerate() {
+        let number = i + 1;
+        let mut tokens = tokenize(text, number)?;
+        if tokens.is_empty() {
+            continue;
+        }
+        let first = word(&tokens[0], number)?.to_string();
+        if !is_mnemonic(&first) {
+            // a label must be followed by a statement, otherwise the first word is more likely a misspelled opcode
+            if tokens.len() > 1 && !is_mnemonic(word(&tokens[1], number)?) {
+                return Err(error(number, AsmErrorKind::UnknownOpcode(first)));
+            }
+            let label = first.trim_end_matches(':').to_string();
+            if origin.is_none() {
+                return Err(error(number, AsmErrorKind::MissingOrig));
+            }
+            if symbols.insert(label.clone(), address as u16).is_some() {
+                return Err(error(number, AsmErrorKind::DuplicateLabel(label)));
+            }
+            symbol_order.push(label);
+            tokens.remove(0);
+            if tokens.is_empty() {
+                continue;
+            }
+        }
+        let mnemonic = word(&tokens[0], number)?.to_string();
+        if !is_mnemonic(&mnemonic) {
+            return Err(error(number, AsmErrorKind::UnknownOpcode(mnemonic)));
+        }
+        let operands = tokens[1..].to_vec();
+        let statement = address as u16;
+        match (mnemonic.to_ascii_uppercase().as_str(), origin) {
+            (".ORIG", None) => {
+                expect(&operands, 1, number)?;
+                let w = word(&operands[0], number)?;
+                let value = parse_number(w).ok_or_else(|| error(number, AsmErrorKind::InvalidNumber(w.to_string())))?;
+                let value = fit(value, 16, false, number)?;
+                origin = Some(value);
+                address = value as u32;
+                continue;
+            }
+            (".ORIG", Some(_)) => return Err(error(number, AsmErrorKind::UnexpectedOrig)),
+            (_, None) => return Err(error(number, AsmErrorKind::MissingOrig)),
+            (".END", _) => break,
+            (".BLKW", _) => address += blkw_size(&operands, number)? as u32,
+            (".STRINGZ", _) => address += stringz(&operands, number)?.len() as u32 + 1,
+            _ => address += 1,
+        }
+        if address > 1 << 16 {
+            return Err(error(number, AsmErrorKind::AddressOverflow));
+        }
+        lines.push(Line { number, address: statement, mnemonic, operands });
+    }
+    let origin = origin.ok_or_else(|| error(source.lines().count().max(1), AsmErrorKind::MissingOrig))?;
+
+    // second pass: encode statements with all labels known
+    let mut obj = vec![origin];
+    for line in &lines {
+        let n = line.number;
+        match line.mnemonic.to_ascii_uppercase().as_str() {
+            ".FILL" => {
+                expect(&line.operands, 1, n)?;
+                let w = word(&line.operands[0], n)?;
+                let value = match symbols.get(w) {
+                    Some(&address) => address,
+                    None => {
+                        let value = parse_number(w).ok_or_else(|| error(n, AsmErrorKind::UndefinedLabel(w.to_string())))?;
+                        if !(-(1 << 15)..1 << 16).contains(&value) {
+                            return Err(error(n, AsmErrorKind::OutOfRange { value, bits: 16 }));
+                        }
+                        value as u16
+                    }
+                };
+                obj.push(value);
+            }
+            ".BLKW" => obj.resize(obj.len() + blkw_size(&line.operands, n)? as usize, 0),
+            ".STRINGZ" => {
+                obj.extend(stringz(&line.operands, n)?.iter().map(|&c| c as u16));
+                obj.push(0);
+            }
+            _ => obj.push(operation(line, &symbols)?.encode().map_err(|e| error(n, AsmErrorKind::Encode(e)))?),
+        }
+    }
+    let symbols = symbol_order.into_iter().map(|label| {
+        let address = symbols[&label];
+        (label, address)
+    });
+    Ok(Program { obj, symbols: symbols.collect() })
+}
+
+/// symbol table in the format written by the reference `lc3as` assembler
+pub fn symbol_table(program: &Program) -> String {
+    let mut table = String::from("// Symbol table\n// Scope level 0:\n//\tSymbol Name       Page Address\n//\t----------------  ------------\n");
+    for (label, address) in &program.symbols {
+        table.push_str(&format!("//\t{:<16}  {:04X}\n", label, address));
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = r#"; every kind of statement
+        .ORIG x3000
+START   LEA R0, MSG             ; comment
+        PUTS
+        BRnz START
+        BRp START
+        BR DONE
+        BRnzp DONE
+        BRz DONE
+        BRn DONE
+        BRzp DONE
+        BRnp DONE
+loop:   add r1, r1, #-1
+DONE    HALT
+        GETC
+        OUT
+        IN
+        PUTSP
+        TRAP x25
+MSG     .STRINGZ "a\"\\\n\t\e\0"
+DATA    .FILL START
+        .FILL #-1
+        .BLKW 2
+        .END
+        anything after .END is ignored
+"#;
+
+    #[test]
+    fn assembles_a_program() {
+        let program = assemble(PROGRAM).unwrap();
+        #[rustfmt::skip]
+        let expected = [
+            0x3000,
+            0xe010, 0xf022, 0x0dfd, 0x03fc, 0x0e06, 0x0e05, 0x0404, 0x0803, 0x0602, 0x0a01, 0x127f,
+            0xf025, 0xf020, 0xf021, 0xf023, 0xf024, 0xf025,
+            0x0061, 0x0022, 0x005c, 0x000a, 0x0009, 0x001b, 0x0000, 0x0000,
+            0x3000, 0xffff, 0x0000, 0x0000,
+        ];
+        assert_eq!(program.obj, expected);
+        let symbols: Vec<(&str, u16)> = program.symbols.iter().map(|(label, address)| (label.as_str(), *address)).collect();
+        assert_eq!(symbols, [("START", 0x3000), ("loop", 0x300a), ("DONE", 0x300b), ("MSG", 0x3011), ("DATA", 0x3019)]);
+    }
+
+    #[test]
+    fn writes_obj_and_sym_files() {
+        let program = assemble(".ORIG x3000\nLOOP BR LOOP\nVALUE .FILL x1234\n.END\n").unwrap();
+        let obj: Vec<u8> = program.obj.iter().flat_map(|w| w.to_be_bytes()).collect();
+        assert_eq!(obj, [0x30, 0x00, 0x0f, 0xff, 0x12, 0x34]);
+        let sym = "// Symbol table\n// Scope level 0:\n//\tSymbol Name       Page Address\n//\t----------------  ------------\n//\tLOOP              3000\n//\tVALUE             3001\n";
+        assert_eq!(symbol_table(&program), sym);
+    }
+
+    #[test]
+    fn parses_numbers() {
+        assert_eq!(parse_number("#10"), Some(10));
+        assert_eq!(parse_number("#-3"), Some(-3));
+        assert_eq!(parse_number("x3000"), Some(0x3000));
+        assert_eq!(parse_number("0x3000"), Some(0x3000));
+        assert_eq!(parse_number("xFFFF"), Some(0xffff));
+        assert_eq!(parse_number("b101"), Some(5));
+        assert_eq!(parse_number("42"), Some(42));
+        assert_eq!(parse_number("x"), None);
+        assert_eq!(parse_number("#1a"), None);
+    }
+
+    fn failure(source: &str) -> (usize, AsmErrorKind) {
+        match assemble(source) {
+            Ok(_) => panic!("assembled: {}", source),
+            Err(AsmError { line, kind }) => (line, kind),
+        }
+    }
+
+    #[test]
+    fn reports_errors_with_their_line() {
+        assert!(matches!(failure("\nADD R0, R0, #1"), (2, AsmErrorKind::MissingOrig)));
+        assert!(matches!(failure("; nothing"), (1, AsmErrorKind::MissingOrig)));
+        assert!(matches!(failure(".ORIG x3000\n.ORIG x4000"), (2, AsmErrorKind::UnexpectedOrig)));
+        assert!(matches!(failure(".ORIG x3000\nHALT\nFOO R1, R2"), (3, AsmErrorKind::UnknownOpcode(op)) if op == "FOO"));
+        assert!(matches!(failure(".ORIG x3000\nTRAP xZZ"), (2, AsmErrorKind::InvalidNumber(value)) if value == "xZZ"));
+        assert!(matches!(failure(".ORIG x3000\nNOT R8, R1"), (2, AsmErrorKind::InvalidRegister(value)) if value == "R8"));
+        assert!(matches!(failure(".ORIG x3000\n.STRINGZ \"a\\q\""), (2, AsmErrorKind::InvalidString)));
+        assert!(matches!(failure(".ORIG x3000\nLD R0, \"a\""), (2, AsmErrorKind::InvalidString)));
+        assert!(matches!(failure(".ORIG x3000\n.STRINGZ \"abc"), (2, AsmErrorKind::UnterminatedString)));
+        assert!(matches!(failure(".ORIG x3000\nADD R0, R1"), (2, AsmErrorKind::OperandCount { expected: 3, actual: 2 })));
+        assert!(matches!(failure(".ORIG x3000\nADD R0, R0, #16"), (2, AsmErrorKind::OutOfRange { value: 16, bits: 5 })));
+        assert!(matches!(failure(".ORIG x3000\nTRAP x100"), (2, AsmErrorKind::OutOfRange { value: 0x100, bits: 8 })));
+        assert!(matches!(failure(".ORIG x3000\nBR FAR\n.BLKW 300\nFAR HALT"), (2, AsmErrorKind::OutOfRange { value: 300, bits: 9 })));
+        assert!(matches!(failure(".ORIG x3000\nA HALT\nA HALT"), (3, AsmErrorKind::DuplicateLabel(label)) if label == "A"));
+        assert!(matches!(failure(".ORIG x3000\nHALT\nBR NOWHERE"), (3, AsmErrorKind::UndefinedLabel(label)) if label == "NOWHERE"));
+        assert!(matches!(failure(".ORIG xFFFF\nHALT\nHALT"), (3, AsmErrorKind::AddressOverflow)));
+    }
+}
@@ -1,8 +1,11 @@
 use core::fmt;
 
+use crate::asm;
 use crate::io;
 use crate::ops;
+use crate::ops_encode;
 use crate::ops_parse;
+use crate::vm;
 use crate::vm_spec;
 
 #[derive(Clone, Copy)]
@@ -10,13 +13,19 @@ pub struct VmInt(pub u16);
 
 impl core::fmt::Debug for ops::Register {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "R{}", self.0)
+        match self.0 {
+            8 => write!(f, "PC"),
+            9 => write!(f, "PSR"),
+            10 => write!(f, "SSP"),
+            11 => write!(f, "USP"),
+            r => write!(f, "R{}", r),
+        }
     }
 }
 
 impl core::fmt::Debug for VmInt {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "${}", if self.0 < 1 << 15 { self.0 as i32 } else { -(!self.0 as i32) })
+        write!(f, "${}", self.0 as i16)
     }
 }
 
@@ -33,7 +42,7 @@ impl core::fmt::Debug for ops::Operation {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
             Self::Add { dr, sr1, arg } => write!(f, "add({:?}, {:?}, {:?})", dr, sr1, arg),
-            Self::And { dr, sr1, arg } => write!(f, "add({:?}, {:?}, {:?})", dr, sr1, arg),
+            Self::And { dr, sr1, arg } => write!(f, "and({:?}, {:?}, {:?})", dr, sr1, arg),
             Self::Br { n, z, p, pc_offset } => write!(f, "br(nzp=({}, {}, {}), {:?})", n, z, p, VmInt(pc_offset)),
             Self::Jmp { base_r } => write!(f, "jmp({:?})", base_r),
             Self::Jsr { pc_offset } => write!(f, "jsr({:?})", VmInt(pc_offset)),
@@ -41,10 +50,10 @@ impl core::fmt::Debug for ops::Operation {
             Self::Ld { dr, pc_offset } => write!(f, "ld({:?}, {:?})", dr, VmInt(pc_offset)),
             Self::Ldi { dr, pc_offset } => write!(f, "ldi({:?}, {:?})", dr, VmInt(pc_offset)),
             Self::Ldr { dr, base_r, offset } => write!(f, "ldr({:?}, {:?}, {:?})", dr, base_r, VmInt(offset)),
-            Self::Lea { dr, pc_offset } => write!(f, "lea({:?}, {:?})", dr, pc_offset),
+            Self::Lea { dr, pc_offset } => write!(f, "lea({:?}, {:?})", dr, VmInt(pc_offset)),
             Self::Not { dr, sr } => write!(f, "not({:?}, {:?})", dr, sr),
             Self::St { sr, pc_offset } => write!(f, "st({:?}, {:?})", sr, VmInt(pc_offset)),
-            Self::Sti { sr, pc_offset } => write!(f, "sti({:?}, {:?})", sr, pc_offset),
+            Self::Sti { sr, pc_offset } => write!(f, "sti({:?}, {:?})", sr, VmInt(pc_offset)),
             Self::Str { sr, base_r, offset } => write!(f, "str({:?}, {:?}, {:?})", sr, base_r, VmInt(offset)),
             Self::Trap { trap_vector } => write!(f, "trap({:#x})", trap_vector),
             Self::Rti => write!(f, "rti"),
@@ -71,17 +80,42 @@ impl fmt::Display for ops_parse::ParseError {
     }
 }
 
+impl fmt::Display for ops_encode::EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::RegisterOutOfRange { register } => write!(f, "register out of range: R{}", register),
+            Self::UnsignedOutOfRange { value, bit_size } => write!(f, "unsigned value out of range: value={:#x}, bits={}", value, bit_size),
+            Self::SignedOutOfRange { value, bit_size } => write!(f, "signed value out of range: value={:?}, bits={}", VmInt(*value), bit_size),
+        }
+    }
+}
+
 impl fmt::Display for io::IoError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.0)
     }
 }
 
+impl fmt::Display for vm::SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "not a vm snapshot"),
+            Self::Version { found, expected } => write!(f, "snapshot format version {} is not supported, expected version {}", found, expected),
+            Self::Truncated => write!(f, "snapshot is truncated"),
+            Self::TrailingData { length } => write!(f, "snapshot has {} unexpected trailing bytes", length),
+        }
+    }
+}
+
 impl fmt::Display for vm_spec::TickError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Self::Io(e) => write!(f, "io error: {}", e),
             Self::Parse(e) => write!(f, "parse error: {}", e),
+            Self::UnhandledException { vector, pc } => write!(f, "unhandled exception: vector={:#04x}, pc={:#06x}", vector, pc),
+            Self::UnhandledTrap { trap_vector, pc } => write!(f, "no routine for trap vector {:#04x}, pc={:#06x}", trap_vector, pc),
+            Self::StackOverflow { sp } => write!(f, "stack pointer {:#06x} reaches into the device registers", sp),
+            Self::Interrupted => write!(f, "interrupted"),
         }
     }
 }
@@ -90,6 +124,44 @@ impl fmt::Display for vm_spec::LoadError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self {
             &Self::EmptyProgram => write!(f, "empty program provided"),
+            Self::Overlap { first, second, address } => write!(f, "objects '{}' and '{}' overlap at {:#06x}", first, second, address),
+            Self::AddressOverflow { name, origin, length, address } => write!(f, "object '{}' (origin={:#06x}, length={}) runs past the end of the address space, up to {:#07x}", name, origin, length, address),
+            Self::DeviceRegion { name, origin, length, address } => write!(f, "object '{}' (origin={:#06x}, length={}) writes into the device region at {:#06x}", name, origin, length, address),
+        }
+    }
+}
+
+impl fmt::Display for asm::AsmErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingOrig => write!(f, "program must start with .ORIG"),
+            Self::UnexpectedOrig => write!(f, "only one .ORIG is allowed"),
+            Self::UnknownOpcode(op) => write!(f, "unknown opcode or directive '{}'", op),
+            Self::InvalidNumber(value) => write!(f, "invalid number '{}'", value),
+            Self::InvalidRegister(value) => write!(f, "invalid register '{}'", value),
+            Self::InvalidString => write!(f, "invalid string literal"),
+            Self::UnterminatedString => write!(f, "unterminated string literal"),
+            Self::OperandCount { expected, actual } => write!(f, "expected {} operand(s), found {}", expected, actual),
+            Self::OutOfRange { value, bits } => write!(f, "value {} does not fit into {} bits", value, bits),
+            Self::DuplicateLabel(label) => write!(f, "duplicate label '{}'", label),
+            Self::UndefinedLabel(label) => write!(f, "undefined label '{}'", label),
+            Self::AddressOverflow => write!(f, "program runs past the end of the address space"),
+            Self::Encode(e) => write!(f, "unable to encode instruction: {}", e),
         }
     }
 }
+
+impl fmt::Display for asm::AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+impl std::error::Error for ops_parse::ParseError {}
+impl std::error::Error for ops_encode::EncodeError {}
+impl std::error::Error for io::IoError {}
+impl std::error::Error for vm::SnapshotError {}
+impl std::error::Error for vm_spec::TickError {}
+impl std::error::Error for vm_spec::LoadError {}
+impl std::error::Error for asm::AsmErrorKind {}
+impl std::error::Error for asm::AsmError {}
@@ -0,0 +1,378 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::asm;
+use crate::disasm;
+use crate::history::History;
+use crate::io;
+use crate::journal::Journaled;
+use crate::ops::*;
+use crate::vm::{Input, VmMem};
+use crate::vm_spec::{self, VmSpec, R_PC, R_PSR};
+use crate::watch::{WatchHit, WatchKind, Watched, Watchpoint};
+
+const HELP: &str = "\
+break <addr|label>        set a breakpoint
+delete <addr|label>       remove a breakpoint
+step [count]              execute instructions
+next                      execute an instruction, stepping over JSR, JSRR and TRAP
+continue                  run until a breakpoint or halt
+reverse-step [count]      undo the last instructions
+reverse-continue          go back to the previous breakpoint
+goto <instruction>        go back to an earlier instruction count
+regs                      print registers and the instruction count
+mem <addr|label> [len]    print memory words
+set reg <name> <value>    change a register (R0-R7, PC, PSR)
+set mem <addr> <value>    change a memory word, without device side effects
+watch <addr|label> [len]  stop after writes to a memory range
+rwatch <addr|label> [len] stop after reads of a memory range
+awatch <addr|label> [len] stop after any access to a memory range
+unwatch <addr|label>      remove the watchpoints covering an address
+disasm [addr] [count]     disassemble memory, around PC by default
+quit                      exit the debugger";
+
+enum Stop {
+    Step,
+    Breakpoint,
+    Watch(Vec<WatchHit>),
+    Halted,
+    Interrupted,
+    Error(vm_spec::TickError),
+}
+
+/// machine as seen by the debugger: accesses are checked against watchpoints and writes are journaled for stepping back
+pub type DebugVm<V> = Watched<Journaled<V>>;
+
+/// interactive debugger; its own i/o goes through stderr and a line-buffered stdin while the guest owns the raw terminal
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    symbols:     disasm::Symbols,
+    labels:      HashMap<String, u16>,
+    history:     History,
+    halted:      bool,
+}
+
+fn parse_value(text: &str) -> Result<u16, String> {
+    match asm::parse_number(text) {
+        Some(value) if (-(1 << 15)..1 << 16).contains(&value) => Ok(value as u16),
+        _ => Err(format!("invalid value '{}'", text)),
+    }
+}
+
+fn parse_register(text: &str) -> Result<Register, String> {
+    match text.to_ascii_uppercase().as_str() {
+        "PC" => Ok(R_PC),
+        "PSR" => Ok(R_PSR),
+        name => match name.as_bytes() {
+            [b'R', d @ b'0'..=b'7'] => Ok(Register((d - b'0') as usize)),
+            _ => Err(format!("unknown register '{}'", text)),
+        },
+    }
+}
+
+/// general purpose registers on one line, PC and PSR with its fields on the next
+pub fn format_regs(vm: &impl VmMem) -> String {
+    let regs: Vec<String> = (0..8).map(|r| format!("R{} x{:04X}", r, vm.read_reg(Register(r)))).collect();
+    let psr = vm.read_reg(R_PSR);
+    let cond: String = [(4, 'n'), (2, 'z'), (1, 'p')].iter().filter(|&&(bit, _)| psr & bit != 0).map(|&(_, c)| c).collect();
+    let mode = if psr & (1 << 15) != 0 { "user" } else { "supervisor" };
+    format!("{}\nPC x{:04X}  PSR x{:04X} ({}, priority {}, cc {})", regs.join("  "), vm.read_reg(R_PC), psr, mode, (psr >> 8) & 0b111, cond)
+}
+
+impl Debugger {
+    /// `vm` is the state the program starts from, the earliest point history can go back to
+    pub fn new(symbols: disasm::Symbols, vm: &mut impl VmMem) -> Self {
+        vm.input_log().recording = true;
+        let labels = symbols.iter().map(|(&address, label)| (label.clone(), address)).collect();
+        Self { breakpoints: BTreeSet::new(), symbols, labels, history: History::new(vm), halted: false }
+    }
+
+    fn parse_address(&self, text: &str) -> Result<u16, String> {
+        match self.labels.get(text) {
+            Some(&address) => Ok(address),
+            None => parse_value(text),
+        }
+    }
+
+    fn format_line(&self, vm: &impl VmMem, address: u16) -> String {
+        let code = vm.peek_mem(address);
+        let label = self.symbols.get(&address).map(String::as_str).unwrap_or("");
+        let marker = match (address == vm.read_reg(R_PC), self.breakpoints.contains(&address)) {
+            (true, _) => "=>",
+            (false, true) => " *",
+            (false, false) => "  ",
+        };
+        format!("{} x{:04X}  x{:04X}  {:<16}{}", marker, address, code, label, disasm::format_word(code, address, &self.symbols))
+    }
+
+    fn tick(&mut self, vm: &mut DebugVm<impl VmMem + Default>) -> Option<Stop> {
+        // writes made by debugger commands are not part of any instruction
+        vm.vm.take_changes();
+        let result = vm.tick_watched();
+        let changes = vm.vm.take_changes();
+        let input = std::mem::take(&mut vm.input_log().recorded);
+        self.history.record(&vm.vm.vm, changes, input);
+        match result {
+            Ok((true, hits)) if hits.is_empty() => None,
+            Ok((true, hits)) => Some(Stop::Watch(hits)),
+            Ok((false, _)) => {
+                self.halted = true;
+                Some(Stop::Halted)
+            }
+            Err(e) => {
+                self.halted = true;
+                Some(Stop::Error(e))
+            }
+        }
+    }
+
+    // runs `steps` instructions, or until a breakpoint, `until` or the end of the program when no count is given;
+    // the breakpoint at the starting pc is skipped. the guest gets the raw terminal for the time it runs
+    fn resume(&mut self, vm: &mut DebugVm<impl VmMem + Default>, steps: Option<u16>, until: Option<u16>) -> Stop {
+        if let Err(e) = io::term_setup() {
+            return Stop::Error(vm_spec::TickError::Io(e));
+        }
+        // a ctrl-c at the prompt is not meant for the run that follows
+        io::take_interrupt();
+        let mut executed = 0u32;
+        let stop = loop {
+            if io::take_interrupt() {
+                break Stop::Interrupted;
+            }
+            if let Some(stop) = self.tick(vm) {
+                break stop;
+            }
+            executed += 1;
+            let pc = vm.read_reg(R_PC);
+            if Some(executed) == steps.map(u32::from) || Some(pc) == until {
+                break Stop::Step;
+            }
+            if steps.is_none() && self.breakpoints.contains(&pc) {
+                break Stop::Breakpoint;
+            }
+        };
+        vm.console().flush().unwrap_or(());
+        io::term_restore().unwrap_or(());
+        stop
+    }
+
+    fn report(&self, vm: &impl VmMem, stop: Stop) {
+        match stop {
+            Stop::Step => {}
+            Stop::Breakpoint => eprintln!("breakpoint x{:04X}", vm.read_reg(R_PC)),
+            Stop::Watch(hits) => {
+                for hit in hits {
+                    let access = if hit.write { format!("write x{:04X} -> x{:04X}", hit.old, hit.new) } else { format!("read x{:04X}", hit.old) };
+                    eprintln!("watchpoint x{:04X}: {} by x{:04X}  {}", hit.address, access, hit.pc, disasm::format_word(hit.code, hit.pc, &self.symbols));
+                }
+            }
+            Stop::Halted => eprintln!("program halted"),
+            Stop::Interrupted => eprintln!("interrupted"),
+            Stop::Error(e) => eprintln!("vm failed: {}", e),
+        }
+        if !self.halted {
+            eprintln!("{}", self.format_line(vm, vm.read_reg(R_PC)));
+        }
+    }
+
+    fn print_regs(&self, vm: &impl VmMem) {
+        eprintln!("{}", format_regs(vm));
+        eprintln!("instruction {} (history back to {})", self.history.count(), self.history.oldest());
+    }
+
+    // runs the instructions from the snapshot restored by `History::restore` up to instruction `target` once more: the
+    // console answers are the ones the program got the first time, and its output is not printed again
+    fn replay(&mut self, vm: &mut DebugVm<impl VmMem + Default>, target: u64, input: Vec<Input>) -> Result<(), vm_spec::TickError> {
+        vm.input_log().replay = Some(input.into());
+        let mut result = Ok(true);
+        while self.history.count() < target && matches!(result, Ok(true)) {
+            // watchpoints were reported the first time already
+            result = vm.vm.tick();
+            let changes = vm.vm.take_changes();
+            let input = std::mem::take(&mut vm.input_log().recorded);
+            self.history.record(&vm.vm.vm, changes, input);
+        }
+        vm.input_log().replay = None;
+        result.map(|_| ())
+    }
+
+    // undoes up to `steps` instructions, or until the pc reaches a breakpoint when no count is given
+    fn reverse(&mut self, vm: &mut DebugVm<impl VmMem + Default>, steps: Option<u16>) {
+        let mut undone = 0;
+        let exhausted = loop {
+            if !self.history.undo(&mut vm.vm.vm) {
+                break true;
+            }
+            self.halted = false;
+            undone += 1;
+            if Some(undone) == steps || (steps.is_none() && self.breakpoints.contains(&vm.read_reg(R_PC))) {
+                break false;
+            }
+        };
+        if exhausted {
+            eprintln!("reached the start of the recorded history");
+        } else if steps.is_none() {
+            eprintln!("breakpoint x{:04X}", vm.read_reg(R_PC));
+        }
+        eprintln!("{}", self.format_line(vm, vm.read_reg(R_PC)));
+    }
+
+    fn command(&mut self, vm: &mut DebugVm<impl VmMem + Default>, words: &[&str]) -> Result<bool, String> {
+        let running = |halted: bool| if halted { Err("program is not running".to_string()) } else { Ok(()) };
+        match words {
+            ["break" | "b", at] => {
+                let address = self.parse_address(at)?;
+                self.breakpoints.insert(address);
+                eprintln!("breakpoint set at x{:04X}", address);
+            }
+            ["delete" | "d", at] => {
+                let address = self.parse_address(at)?;
+                if !self.breakpoints.remove(&address) {
+                    return Err(format!("no breakpoint at x{:04X}", address));
+                }
+            }
+            ["step" | "s", count @ ..] if count.len() <= 1 => {
+                running(self.halted)?;
+                let count = count.first().map(|c| parse_value(c)).transpose()?.unwrap_or(1);
+                let stop = self.resume(vm, Some(count.max(1)), None);
+                self.report(vm, stop);
+            }
+            ["next" | "n"] => {
+                running(self.halted)?;
+                let pc = vm.read_reg(R_PC);
+                let stop = match Operation::parse(vm.peek_mem(pc)) {
+                    Ok(Operation::Jsr { .. } | Operation::Jsrr { .. } | Operation::Trap { .. }) => self.resume(vm, None, Some(pc.wrapping_add(1))),
+                    _ => self.resume(vm, Some(1), None),
+                };
+                self.report(vm, stop);
+            }
+            ["continue" | "c"] => {
+                running(self.halted)?;
+                let stop = self.resume(vm, None, None);
+                self.report(vm, stop);
+            }
+            ["reverse-step" | "rs", count @ ..] if count.len() <= 1 => {
+                let count = count.first().map(|c| parse_value(c)).transpose()?.unwrap_or(1);
+                self.reverse(vm, Some(count.max(1)));
+            }
+            ["reverse-continue" | "rc"] => self.reverse(vm, None),
+            ["goto", count] => {
+                let target = count.parse::<u64>().map_err(|_| format!("invalid instruction count '{}'", count))?;
+                if target > self.history.count() {
+                    return Err(format!("instruction {} has not been executed yet", target));
+                }
+                if !self.history.rewind(&mut vm.vm.vm, target) {
+                    let input = self.history.restore(&mut vm.vm.vm, target).ok_or_else(|| format!("history does not reach back to instruction {}", target))?;
+                    vm.vm.take_changes();
+                    self.replay(vm, target, input).map_err(|e| format!("replay to instruction {} failed: {}", target, e))?;
+                }
+                self.halted = false;
+                eprintln!("{}", self.format_line(vm, vm.read_reg(R_PC)));
+            }
+            ["regs" | "r"] => self.print_regs(vm),
+            ["mem" | "x", at, len @ ..] if len.len() <= 1 => {
+                let address = self.parse_address(at)?;
+                let len = len.first().map(|l| parse_value(l)).transpose()?.unwrap_or(1);
+                for i in 0..len {
+                    let a = address.wrapping_add(i);
+                    let label = self.symbols.get(&a).map(String::as_str).unwrap_or("");
+                    eprintln!("x{:04X}  x{:04X}  {:<16}{}", a, vm.peek_mem(a), label, vm.peek_mem(a) as i16);
+                }
+            }
+            [command @ ("watch" | "rwatch" | "awatch"), at, len @ ..] if len.len() <= 1 => {
+                let address = self.parse_address(at)?;
+                let len = len.first().map(|l| parse_value(l)).transpose()?.unwrap_or(1).max(1);
+                let kind = match *command {
+                    "watch" => WatchKind::Write,
+                    "rwatch" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                let end = address.checked_add(len - 1).ok_or_else(|| "watched range exceeds the address space".to_string())?;
+                vm.add_watchpoint(Watchpoint { range: address..=end, kind });
+                eprintln!("watchpoint set at x{:04X}-x{:04X}", address, end);
+            }
+            ["unwatch", at] => {
+                let address = self.parse_address(at)?;
+                if !vm.remove_watchpoints(address) {
+                    return Err(format!("no watchpoint at x{:04X}", address));
+                }
+            }
+            ["set", "reg", name, value] => vm.write_reg(parse_register(name)?, parse_value(value)?),
+            ["set", "mem", at, value] => vm.poke_mem(self.parse_address(at)?, parse_value(value)?),
+            ["disasm", args @ ..] if args.len() <= 2 => {
+                let pc = vm.read_reg(R_PC);
+                let start = args.first().map(|a| self.parse_address(a)).transpose()?.unwrap_or(pc.wrapping_sub(3));
+                let count = args.get(1).map(|c| parse_value(c)).transpose()?.unwrap_or(8);
+                for i in 0..count {
+                    eprintln!("{}", self.format_line(vm, start.wrapping_add(i)));
+                }
+            }
+            ["help" | "h"] => eprintln!("{}", HELP),
+            ["quit" | "q"] => return Ok(false),
+            _ => return Err(format!("unknown command '{}', try 'help'", words.join(" "))),
+        }
+        Ok(true)
+    }
+
+    pub fn run(&mut self, vm: &mut DebugVm<impl VmMem + Default>) -> Result<(), io::IoError> {
+        io::term_restore()?;
+        eprintln!("{}", self.format_line(vm, vm.read_reg(R_PC)));
+        let mut last = String::new();
+        loop {
+            eprint!("(lc3) ");
+            let mut line = String::new();
+            if io::read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            // an empty line repeats the previous command, which is handy for stepping
+            if line.trim().is_empty() {
+                line = last.clone();
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() {
+                continue;
+            }
+            match self.command(vm, &words) {
+                Ok(true) => {}
+                Ok(false) => return Ok(()),
+                Err(message) => eprintln!("{}", message),
+            }
+            last = line;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::Vm;
+    use crate::vm_spec::Object;
+
+    #[test]
+    fn replays_from_snapshots_with_the_same_input() {
+        // echoes keys: LDI R0, KBSR; BRzp #-2; LDI R1, KBDR; STI R1, DDR; ADD R2, R2, #1; BR #-6; xFE00; xFE02; xFE06
+        let program = [0x3000, 0xa005, 0x07fe, 0xa204, 0xb204, 0x14a1, 0x0ffa, 0xfe00, 0xfe02, 0xfe06];
+        let mut vm: DebugVm<Vm<io::Buffers>> = Watched::new(Journaled::new(VmSpec::load(&[Object { name: "test", obj: &program }]).unwrap()));
+        vm.vm.vm.console_mut().input.extend(b"a");
+        let mut debugger = Debugger::new(disasm::Symbols::new(), &mut vm);
+        let mut regs = String::new();
+        while debugger.history.count() < 400_000 {
+            match debugger.history.count() {
+                // the key arrives while the program polls, between two snapshots and long before the undo log starts
+                140_000 => vm.vm.vm.console_mut().input.extend(b"b"),
+                150_000 => regs = format_regs(&vm),
+                _ => {}
+            }
+            assert!(debugger.tick(&mut vm).is_none());
+        }
+        assert_eq!(vm.vm.vm.console_mut().output, b"ab");
+
+        assert!(matches!(debugger.command(&mut vm, &["goto", "150000"]), Ok(true)));
+        assert_eq!(debugger.history.count(), 150_000);
+        assert_eq!(format_regs(&vm), regs);
+        assert_eq!(vm.read_reg(Register(1)), b'b' as u16);
+        assert_eq!(vm.vm.vm.console_mut().output, b"ab");
+        // the replayed instructions can be undone again
+        assert!(matches!(debugger.command(&mut vm, &["goto", "140000"]), Ok(true)));
+        assert_eq!(vm.read_reg(Register(1)), b'a' as u16);
+    }
+}
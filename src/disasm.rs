@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use crate::ops::*;
+
+pub type Symbols = HashMap<u16, String>;
+
+/// reads symbol tables in the format written by `lc3as` (and `asm::symbol_table`)
+pub fn parse_symbols(text: &str) -> Symbols {
+    let mut symbols = Symbols::new();
+    for line in text.lines() {
+        let fields: Vec<&str> = line.trim_start_matches('/').split_whitespace().collect();
+        if let [name, address] = fields[..] {
+            if let Ok(address) = u16::from_str_radix(address, 16) {
+                symbols.insert(address, name.to_string());
+            }
+        }
+    }
+    symbols
+}
+
+fn target(address: u16, pc_offset: u16, symbols: &Symbols) -> String {
+    let target = address.wrapping_add(1).wrapping_add(pc_offset);
+    match symbols.get(&target) {
+        Some(label) => label.clone(),
+        None => format!("x{:04X}", target),
+    }
+}
+
+fn immediate(value: u16) -> String {
+    format!("#{}", value as i16)
+}
+
+fn argument(arg: Argument) -> String {
+    match arg {
+        Argument::Register(r) => format!("{:?}", r),
+        Argument::Immediate(imm) => immediate(imm),
+    }
+}
+
+/// canonical LC-3 assembly for `op` located at `address`, with pc-relative operands resolved to absolute targets
+pub fn format_op(op: Operation, address: u16, symbols: &Symbols) -> String {
+    match op {
+        Operation::Add { dr, sr1, arg } => format!("ADD {:?}, {:?}, {}", dr, sr1, argument(arg)),
+        Operation::And { dr, sr1, arg } => format!("AND {:?}, {:?}, {}", dr, sr1, argument(arg)),
+        // never branches, so this is data (x0000 most of all) rather than code; the assembler has no NOP to write it back
+        Operation::Br { n: false, z: false, p: false, pc_offset } => format!(".FILL x{:04X}", pc_offset & 0x1ff),
+        Operation::Br { n, z, p, pc_offset } => {
+            let flags: String = [(n, 'n'), (z, 'z'), (p, 'p')].iter().filter(|(set, _)| *set).map(|&(_, c)| c).collect();
+            format!("BR{} {}", flags, target(address, pc_offset, symbols))
+        }
+        Operation::Jmp { base_r: Register(7) } => "RET".to_string(),
+        Operation::Jmp { base_r } => format!("JMP {:?}", base_r),
+        Operation::Jsr { pc_offset } => format!("JSR {}", target(address, pc_offset, symbols)),
+        Operation::Jsrr { base_r } => format!("JSRR {:?}", base_r),
+        Operation::Ld { dr, pc_offset } => format!("LD {:?}, {}", dr, target(address, pc_offset, symbols)),
+        Operation::Ldi { dr, pc_offset } => format!("LDI {:?}, {}", dr, target(address, pc_offset, symbols)),
+        Operation::Ldr { dr, base_r, offset } => format!("LDR {:?}, {:?}, {}", dr, base_r, immediate(offset)),
+        Operation::Lea { dr, pc_offset } => format!("LEA {:?}, {}", dr, target(address, pc_offset, symbols)),
+        Operation::Not { dr, sr } => format!("NOT {:?}, {:?}", dr, sr),
+        Operation::St { sr, pc_offset } => format!("ST {:?}, {}", sr, target(address, pc_offset, symbols)),
+        Operation::Sti { sr, pc_offset } => format!("STI {:?}, {}", sr, target(address, pc_offset, symbols)),
+        Operation::Str { sr, base_r, offset } => format!("STR {:?}, {:?}, {}", sr, base_r, immediate(offset)),
+        Operation::Trap { trap_vector: 0x20 } => "GETC".to_string(),
+        Operation::Trap { trap_vector: 0x21 } => "OUT".to_string(),
+        Operation::Trap { trap_vector: 0x22 } => "PUTS".to_string(),
+        Operation::Trap { trap_vector: 0x23 } => "IN".to_string(),
+        Operation::Trap { trap_vector: 0x24 } => "PUTSP".to_string(),
+        Operation::Trap { trap_vector: 0x25 } => "HALT".to_string(),
+        Operation::Trap { trap_vector } => format!("TRAP x{:02X}", trap_vector),
+        Operation::Rti => "RTI".to_string(),
+    }
+}
+
+/// canonical LC-3 assembly for the word at `address`, falling back to `.FILL` for words that do not decode
+pub fn format_word(code: u16, address: u16, symbols: &Symbols) -> String {
+    match Operation::parse(code) {
+        Ok(op) => format_op(op, address, symbols),
+        Err(_) => format!(".FILL x{:04X}", code),
+    }
+}
+
+/// listing of an object image (origin followed by words): address, raw word, label and instruction per line
+pub fn disassemble(obj: &[u16], symbols: &Symbols) -> String {
+    let mut listing = String::new();
+    let Some((&origin, words)) = obj.split_first() else {
+        return listing;
+    };
+    for (i, &code) in words.iter().enumerate() {
+        let address = origin.wrapping_add(i as u16);
+        let label = symbols.get(&address).map(String::as_str).unwrap_or("");
+        listing.push_str(&format!("x{:04X}  x{:04X}  {:<16}{}\n", address, code, label, format_word(code, address, symbols)));
+    }
+    listing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_branches() {
+        let symbols = Symbols::from([(0x3005, "LOOP".to_string())]);
+        assert_eq!(format_word(0x0e04, 0x3000, &symbols), "BRnzp LOOP");
+        assert_eq!(format_word(0x09ff, 0x3000, &symbols), "BRn x3000");
+        assert_eq!(format_word(0x0000, 0x3000, &symbols), ".FILL x0000");
+        assert_eq!(format_word(0x0104, 0x3000, &symbols), ".FILL x0104");
+    }
+
+    #[test]
+    fn lists_objects() {
+        let symbols = parse_symbols("// Symbol table\n//\tSymbol Name       Page Address\n//\tSTART             3000\n");
+        let listing = disassemble(&[0x3000, 0xf025, 0xd000], &symbols);
+        assert_eq!(listing, "x3000  xF025  START           HALT\nx3001  xD000                  .FILL xD000\n");
+    }
+}
@@ -0,0 +1,404 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+
+use crate::io;
+use crate::ops::*;
+use crate::vm::VmMem;
+use crate::vm_spec::{VmSpec, R_PC, R_PSR};
+
+// registers in the order of `TARGET_XML`: R0-R7, PC, PSR
+const GDB_REGISTERS: [Register; 10] = [Register(0), Register(1), Register(2), Register(3), Register(4), Register(5), Register(6), Register(7), R_PC, R_PSR];
+
+// ticks between checks for an interrupt request (0x03) from the client while the program runs
+const INTERRUPT_POLL_TICKS: u32 = 1 << 12;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.lc3.core">
+    <reg name="r0" bitsize="16" type="int16" regnum="0"/>
+    <reg name="r1" bitsize="16" type="int16"/>
+    <reg name="r2" bitsize="16" type="int16"/>
+    <reg name="r3" bitsize="16" type="int16"/>
+    <reg name="r4" bitsize="16" type="int16"/>
+    <reg name="r5" bitsize="16" type="int16"/>
+    <reg name="r6" bitsize="16" type="data_ptr"/>
+    <reg name="r7" bitsize="16" type="code_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="psr" bitsize="16" type="uint16"/>
+  </feature>
+</target>
+"#;
+
+/// byte stream a gdb client is connected through
+pub trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+enum Stop {
+    Trap,
+    Interrupted,
+    Halted,
+    Error,
+}
+
+/// gdb remote serial protocol stub. addresses are word addresses and every word (register or memory) is sent as
+/// two big-endian bytes, so `m 3000,4` returns the words at x3000 and x3001
+pub struct GdbStub<C: Connection> {
+    connection:  C,
+    breakpoints: BTreeSet<u16>,
+    halted:      bool,
+    // bytes read while looking for an interrupt request, to be handed to `read_packet`
+    pending:     VecDeque<u8>,
+}
+
+fn hex_words(words: impl Iterator<Item = u16>) -> String {
+    words.map(|w| format!("{:04x}", w)).collect()
+}
+
+fn parse_hex(text: &[u8]) -> Option<u16> {
+    u16::from_str_radix(std::str::from_utf8(text).ok()?, 16).ok()
+}
+
+fn parse_words(text: &[u8]) -> Option<Vec<u16>> {
+    if !text.len().is_multiple_of(4) {
+        return None;
+    }
+    text.chunks(4).map(parse_hex).collect()
+}
+
+fn parse_pair(text: &[u8]) -> Option<(u16, u16)> {
+    let mut parts = text.splitn(2, |&c| c == b',');
+    Some((parse_hex(parts.next()?)?, parse_hex(parts.next()?)?))
+}
+
+// parses "addr,len" into a word address and a word count (byte lengths are rounded up to whole words)
+fn parse_range(text: &[u8]) -> Option<(u16, u16)> {
+    parse_pair(text).map(|(address, bytes)| (address, bytes.div_ceil(2)))
+}
+
+impl<C: Connection> GdbStub<C> {
+    pub fn new(connection: C) -> Self {
+        Self { connection, breakpoints: BTreeSet::new(), halted: false, pending: VecDeque::new() }
+    }
+
+    fn read_byte(&mut self) -> std::io::Result<Option<u8>> {
+        if let Some(c) = self.pending.pop_front() {
+            return Ok(Some(c));
+        }
+        let mut buf = [0u8];
+        match self.connection.read(&mut buf)? {
+            0 => Ok(None),
+            _ => Ok(Some(buf[0])),
+        }
+    }
+
+    // returns the payload of the next packet, acknowledging it; None once the client disconnected
+    fn read_packet(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                Some(0x03) => return Ok(Some(vec![0x03])),
+                Some(_) => continue,
+            }
+            let mut payload = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(c) => payload.push(c),
+                }
+            }
+            let (Some(hi), Some(lo)) = (self.read_byte()?, self.read_byte()?) else {
+                return Ok(None);
+            };
+            let checksum = payload.iter().fold(0u8, |sum, &c| sum.wrapping_add(c));
+            if parse_hex(&[hi, lo]) == Some(checksum as u16) {
+                self.connection.write_all(b"+")?;
+                return Ok(Some(payload));
+            }
+            self.connection.write_all(b"-")?;
+        }
+    }
+
+    fn write_packet(&mut self, payload: &str) -> std::io::Result<()> {
+        let checksum = payload.bytes().fold(0u8, |sum, c| sum.wrapping_add(c));
+        self.connection.write_all(format!("${}#{:02x}", payload, checksum).as_bytes())?;
+        // the acknowledgement is not interesting as packets are never resent
+        self.read_byte()?;
+        Ok(())
+    }
+
+    fn interrupt_requested(&mut self) -> std::io::Result<bool> {
+        let mut buf = [0u8];
+        self.connection.set_nonblocking(true)?;
+        let byte = match self.connection.read(&mut buf).map(|n| buf[..n].first().copied()) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => None,
+            other => other?,
+        };
+        self.connection.set_nonblocking(false)?;
+        match byte {
+            Some(0x03) => Ok(true),
+            Some(c) => {
+                self.pending.push_back(c);
+                Ok(false)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn tick(&mut self, vm: &mut (impl VmSpec + VmMem)) -> Option<Stop> {
+        match vm.tick() {
+            Ok(true) => None,
+            Ok(false) => {
+                self.halted = true;
+                Some(Stop::Halted)
+            }
+            Err(e) => {
+                eprintln!("vm failed: {}", e);
+                self.halted = true;
+                Some(Stop::Error)
+            }
+        }
+    }
+
+    fn resume(&mut self, vm: &mut (impl VmSpec + VmMem)) -> std::io::Result<Stop> {
+        let mut ticks = 0u32;
+        loop {
+            if let Some(stop) = self.tick(vm) {
+                return Ok(stop);
+            }
+            if self.breakpoints.contains(&vm.read_reg(R_PC)) {
+                return Ok(Stop::Trap);
+            }
+            ticks = ticks.wrapping_add(1);
+            if ticks.is_multiple_of(INTERRUPT_POLL_TICKS) && self.interrupt_requested()? {
+                return Ok(Stop::Interrupted);
+            }
+        }
+    }
+
+    fn stop_reply(stop: Stop) -> String {
+        match stop {
+            Stop::Trap => "S05".to_string(),
+            Stop::Interrupted => "S02".to_string(),
+            Stop::Halted => "W00".to_string(),
+            Stop::Error => "X06".to_string(),
+        }
+    }
+
+    // answers a single packet; returns false when the client detached or killed the program
+    fn handle(&mut self, vm: &mut (impl VmSpec + VmMem), packet: &[u8]) -> std::io::Result<bool> {
+        let (&command, args) = match packet.split_first() {
+            Some(split) => split,
+            None => return self.write_packet("").map(|_| true),
+        };
+        let reply = match command {
+            b'?' if self.halted => "W00".to_string(),
+            b'?' => "S05".to_string(),
+            b'g' => hex_words(GDB_REGISTERS.iter().map(|&r| vm.read_reg(r))),
+            b'G' => match parse_words(args) {
+                Some(values) if values.len() == GDB_REGISTERS.len() => {
+                    GDB_REGISTERS.iter().zip(values).for_each(|(&r, value)| vm.write_reg(r, value));
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            b'm' => match parse_range(args) {
+                Some((address, count)) => hex_words((0..count).map(|i| vm.peek_mem(address.wrapping_add(i)))),
+                None => "E01".to_string(),
+            },
+            b'M' => {
+                let mut parts = args.splitn(2, |&c| c == b':');
+                match (parts.next().and_then(parse_range), parts.next().and_then(parse_words)) {
+                    (Some((address, count)), Some(values)) if values.len() == count as usize => {
+                        values.iter().enumerate().for_each(|(i, &value)| vm.poke_mem(address.wrapping_add(i as u16), value));
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            b's' | b'c' if self.halted => "W00".to_string(),
+            b's' => {
+                let stop = self.tick(vm).unwrap_or(Stop::Trap);
+                vm.console().flush().map_err(|e| e.0)?;
+                Self::stop_reply(stop)
+            }
+            b'c' => {
+                let stop = self.resume(vm)?;
+                vm.console().flush().map_err(|e| e.0)?;
+                Self::stop_reply(stop)
+            }
+            0x03 => "S02".to_string(),
+            b'Z' | b'z' if args.starts_with(b"0,") => match parse_pair(&args[2..]) {
+                Some((address, _)) => {
+                    if command == b'Z' {
+                        self.breakpoints.insert(address);
+                    } else {
+                        self.breakpoints.remove(&address);
+                    }
+                    "OK".to_string()
+                }
+                None => "E01".to_string(),
+            },
+            b'H' => "OK".to_string(),
+            b'D' => {
+                self.write_packet("OK")?;
+                return Ok(false);
+            }
+            b'k' => return Ok(false),
+            b'q' if args.starts_with(b"Supported") => "PacketSize=1000;qXfer:features:read+".to_string(),
+            b'q' if args == b"Attached" => "1".to_string(),
+            b'q' if args.starts_with(b"Xfer:features:read:target.xml:") => {
+                match parse_pair(&args[b"Xfer:features:read:target.xml:".len()..]) {
+                    Some((offset, length)) => {
+                        let start = (offset as usize).min(TARGET_XML.len());
+                        let end = (start + length as usize).min(TARGET_XML.len());
+                        format!("{}{}", if end == TARGET_XML.len() { 'l' } else { 'm' }, &TARGET_XML[start..end])
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            _ => String::new(),
+        };
+        self.write_packet(&reply)?;
+        Ok(true)
+    }
+
+    /// serves the client until it detaches, kills the program or disconnects
+    pub fn serve(&mut self, vm: &mut (impl VmSpec + VmMem)) -> Result<(), io::IoError> {
+        while let Some(packet) = self.read_packet().map_err(io::IoError)? {
+            if !self.handle(vm, &packet).map_err(io::IoError)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{self, DDR};
+    use crate::vm_spec::Object;
+
+    // AND R0,R0,#0; ADD R0,R0,#1; ADD R0,R0,#1; HALT
+    const PROGRAM: [u16; 5] = [0x3000, 0x5020, 0x1021, 0x1021, 0xf025];
+
+    struct Client(UnixStream);
+
+    impl Client {
+        fn read_byte(&mut self) -> u8 {
+            let mut buf = [0u8];
+            self.0.read_exact(&mut buf).unwrap();
+            buf[0]
+        }
+
+        fn send(&mut self, payload: &str) {
+            let checksum = payload.bytes().fold(0u8, |sum, c| sum.wrapping_add(c));
+            self.0.write_all(format!("${}#{:02x}", payload, checksum).as_bytes()).unwrap();
+            assert_eq!(self.read_byte(), b'+');
+        }
+
+        fn request(&mut self, payload: &str) -> String {
+            self.send(payload);
+            assert_eq!(self.read_byte(), b'$');
+            let mut reply = Vec::new();
+            loop {
+                match self.read_byte() {
+                    b'#' => break,
+                    c => reply.push(c),
+                }
+            }
+            let checksum = reply.iter().fold(0u8, |sum, &c| sum.wrapping_add(c));
+            let (hi, lo) = (self.read_byte(), self.read_byte());
+            assert_eq!(parse_hex(&[hi, lo]), Some(checksum as u16));
+            self.0.write_all(b"+").unwrap();
+            String::from_utf8(reply).unwrap()
+        }
+    }
+
+    #[test]
+    fn serves_a_session() {
+        let (stub_side, client_side) = UnixStream::pair().unwrap();
+        let stub = std::thread::spawn(move || {
+            let mut vm: vm::Vm<io::Buffers> = VmSpec::load(&[Object { name: "test", obj: &PROGRAM }]).unwrap();
+            GdbStub::new(stub_side).serve(&mut vm).unwrap();
+            vm
+        });
+        let mut client = Client(client_side);
+
+        assert!(client.request("qSupported:xmlRegisters=i386").contains("qXfer:features:read+"));
+        assert!(client.request("qXfer:features:read:target.xml:0,1000").starts_with("l<?xml"));
+        assert_eq!(client.request("?"), "S05");
+        assert_eq!(&client.request("g")[32..36], "3000");
+        assert_eq!(client.request("m3000,4"), "50201021");
+        assert_eq!(client.request("M3010,4:12345678"), "OK");
+        assert_eq!(client.request("m3010,4"), "12345678");
+        assert_eq!(client.request(&format!("M{:x},2:0041", DDR)), "OK");
+        assert_eq!(client.request("Z0,3002,1"), "OK");
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(&client.request("g")[..4], "0001");
+        assert_eq!(&client.request("g")[32..36], "3002");
+        assert_eq!(client.request("z0,3002,1"), "OK");
+        assert_eq!(client.request("s"), "S05");
+        assert_eq!(client.request("c"), "W00");
+        assert_eq!(&client.request("g")[..4], "0002");
+        assert_eq!(client.request("?"), "W00");
+        client.send("k");
+
+        let mut vm = stub.join().unwrap();
+        assert_eq!(vm.peek_mem(DDR), 0x41);
+        // memory written by the client does not reach the display
+        assert!(vm.console_mut().output.is_empty());
+    }
+
+    #[test]
+    fn keeps_bytes_that_are_no_interrupt_request() {
+        let (stub_side, client_side) = UnixStream::pair().unwrap();
+        let mut stub = GdbStub::new(stub_side);
+        let mut client = Client(client_side);
+
+        assert!(!stub.interrupt_requested().unwrap());
+        client.0.write_all(b"$?#3f").unwrap();
+        assert!(!stub.interrupt_requested().unwrap());
+        assert_eq!(stub.read_packet().unwrap(), Some(b"?".to_vec()));
+        assert_eq!(client.read_byte(), b'+');
+        client.0.write_all(&[0x03]).unwrap();
+        assert!(stub.interrupt_requested().unwrap());
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        let (stub_side, client_side) = UnixStream::pair().unwrap();
+        let stub = std::thread::spawn(move || {
+            let mut vm: vm::Vm<io::Buffers> = VmSpec::load(&[Object { name: "test", obj: &PROGRAM }]).unwrap();
+            GdbStub::new(stub_side).serve(&mut vm).unwrap();
+        });
+        let mut client = Client(client_side);
+
+        client.0.write_all(b"$g#00").unwrap();
+        assert_eq!(client.read_byte(), b'-');
+        assert_eq!(client.request("m3000"), "E01");
+        assert_eq!(client.request("M3000,4:1234"), "E01");
+        assert_eq!(client.request("G0000"), "E01");
+        assert_eq!(client.request("vMustReplyEmpty"), "");
+        assert_eq!(client.request("D"), "OK");
+        stub.join().unwrap();
+    }
+}
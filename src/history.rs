@@ -0,0 +1,179 @@
+use std::collections::VecDeque;
+
+use crate::journal::Change;
+use crate::ops::*;
+use crate::vm::{Input, VmMem, MEMORY_MAX, REGISTERS};
+
+// instructions whose writes are kept for undoing them one at a time
+const UNDO_TICKS: usize = 1 << 18;
+// instructions between full snapshots, which keep coarse history once the undo log dropped it
+const CHECKPOINT_INTERVAL: u64 = 1 << 16;
+const CHECKPOINTS: usize = 64;
+
+struct Checkpoint {
+    count:     u64,
+    memory:    Vec<u16>,
+    registers: [u16; REGISTERS],
+    // console answers of the instructions executed since, to replay them
+    input:     Vec<Input>,
+}
+
+impl Checkpoint {
+    fn take(count: u64, vm: &impl VmMem) -> Self {
+        let memory = (0..MEMORY_MAX).map(|a| vm.peek_mem(a as u16)).collect();
+        let registers = std::array::from_fn(|r| vm.read_reg(Register(r)));
+        Self { count, memory, registers, input: Vec::new() }
+    }
+    fn restore(&self, vm: &mut impl VmMem) {
+        self.memory.iter().enumerate().for_each(|(a, &value)| vm.poke_mem(a as u16, value));
+        self.registers.iter().enumerate().for_each(|(r, &value)| vm.write_reg(Register(r), value));
+    }
+}
+
+/// execution history for stepping backwards: the writes of the most recent instructions with the values they replaced,
+/// plus periodic snapshots of the whole machine along with the console input read after them, to replay up to any
+/// instruction since. device side effects (printed output, consumed input) are not undone
+pub struct History {
+    // writes of each instruction and the number of console answers it got
+    undo:        VecDeque<(Vec<Change>, usize)>,
+    count:       u64,
+    checkpoints: VecDeque<Checkpoint>,
+}
+
+impl History {
+    pub fn new(vm: &impl VmMem) -> Self {
+        Self { undo: VecDeque::new(), count: 0, checkpoints: VecDeque::from([Checkpoint::take(0, vm)]) }
+    }
+
+    /// number of instructions executed so far
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// oldest instruction count that can be reached exactly
+    pub fn oldest(&self) -> u64 {
+        self.count - self.undo.len() as u64
+    }
+
+    /// records the writes of an instruction that just executed on `vm` and the console answers it got
+    pub fn record(&mut self, vm: &impl VmMem, changes: Vec<Change>, input: Vec<Input>) {
+        if self.undo.len() == UNDO_TICKS {
+            self.undo.pop_front();
+        }
+        self.undo.push_back((changes, input.len()));
+        // there always is a checkpoint at or before the current instruction
+        self.checkpoints.back_mut().unwrap().input.extend(input);
+        self.count += 1;
+        if self.count.is_multiple_of(CHECKPOINT_INTERVAL) {
+            if self.checkpoints.len() == CHECKPOINTS {
+                self.checkpoints.pop_front();
+            }
+            self.checkpoints.push_back(Checkpoint::take(self.count, vm));
+        }
+    }
+
+    /// reverts the last recorded instruction; false when the undo log is exhausted
+    pub fn undo(&mut self, vm: &mut impl VmMem) -> bool {
+        let Some((changes, inputs)) = self.undo.pop_back() else {
+            return false;
+        };
+        for change in changes.iter().rev() {
+            match *change {
+                Change::Reg { register, old, .. } => vm.write_reg(register, old),
+                Change::Mem { address, old, .. } => vm.poke_mem(address, old),
+            }
+        }
+        self.count -= 1;
+        // snapshots of the abandoned future are of no use anymore
+        while self.checkpoints.back().is_some_and(|c| c.count > self.count) {
+            self.checkpoints.pop_back();
+        }
+        let input = &mut self.checkpoints.back_mut().unwrap().input;
+        input.truncate(input.len() - inputs);
+        true
+    }
+
+    /// goes back to instruction `target` through the undo log; returns false and leaves `vm` as it is when the log does
+    /// not reach that far
+    pub fn rewind(&mut self, vm: &mut impl VmMem, target: u64) -> bool {
+        if target > self.count || target < self.oldest() {
+            return false;
+        }
+        while self.count > target {
+            self.undo(vm);
+        }
+        true
+    }
+
+    /// restores the latest snapshot at or before instruction `target`, from where the instructions up to `target` are
+    /// replayed with the returned console answers; none when history does not reach back to `target`
+    pub fn restore(&mut self, vm: &mut impl VmMem, target: u64) -> Option<Vec<Input>> {
+        let index = self.checkpoints.iter().rposition(|c| c.count <= target)?;
+        self.checkpoints.truncate(index + 1);
+        let checkpoint = &mut self.checkpoints[index];
+        checkpoint.restore(vm);
+        self.count = checkpoint.count;
+        self.undo.clear();
+        Some(std::mem::take(&mut checkpoint.input))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io;
+    use crate::vm::Vm;
+
+    const ADDRESS: u16 = 0x3000;
+
+    fn value(count: u64) -> u16 {
+        (count % 60000) as u16 + 1
+    }
+
+    // runs `count` instructions that each store a new value at ADDRESS
+    fn run(vm: &mut Vm<io::Buffers>, history: &mut History, count: u64) {
+        for _ in 0..count {
+            let (old, new) = (vm.peek_mem(ADDRESS), value(history.count() + 1));
+            vm.poke_mem(ADDRESS, new);
+            history.record(vm, vec![Change::Mem { address: ADDRESS, old, new }], vec![Input::Ready(false)]);
+        }
+    }
+
+    #[test]
+    fn undoes_instructions_exactly() {
+        let mut vm: Vm<io::Buffers> = Vm::default();
+        let mut history = History::new(&vm);
+        run(&mut vm, &mut history, 3);
+        assert!(history.rewind(&mut vm, 1));
+        assert_eq!((history.count(), vm.peek_mem(ADDRESS)), (1, value(1)));
+        assert!(history.undo(&mut vm));
+        assert_eq!((history.count(), vm.peek_mem(ADDRESS)), (0, 0));
+        assert!(!history.undo(&mut vm));
+        assert!(!history.rewind(&mut vm, 1));
+    }
+
+    #[test]
+    fn restores_snapshots_beyond_the_undo_log() {
+        let mut vm: Vm<io::Buffers> = Vm::default();
+        let mut history = History::new(&vm);
+        let count = UNDO_TICKS as u64 + 2 * CHECKPOINT_INTERVAL;
+        run(&mut vm, &mut history, count);
+        assert_eq!(history.oldest(), 2 * CHECKPOINT_INTERVAL);
+
+        let target = CHECKPOINT_INTERVAL + 5;
+        assert!(!history.rewind(&mut vm, target));
+        assert_eq!((history.count(), vm.peek_mem(ADDRESS)), (count, value(count)));
+
+        let input = history.restore(&mut vm, target).unwrap();
+        assert_eq!((history.count(), vm.peek_mem(ADDRESS)), (CHECKPOINT_INTERVAL, value(CHECKPOINT_INTERVAL)));
+        assert_eq!(input.len() as u64, CHECKPOINT_INTERVAL);
+        assert!(!history.undo(&mut vm));
+        // replaying records the instructions again
+        run(&mut vm, &mut history, 5);
+        assert!(history.rewind(&mut vm, CHECKPOINT_INTERVAL + 2));
+        assert_eq!(vm.peek_mem(ADDRESS), value(CHECKPOINT_INTERVAL + 2));
+        assert_eq!(history.restore(&mut vm, CHECKPOINT_INTERVAL + 2).map(|input| input.len()), Some(2));
+        assert!(history.restore(&mut vm, 0).is_some());
+        assert_eq!(vm.peek_mem(ADDRESS), 0);
+    }
+}
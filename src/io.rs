@@ -1,5 +1,21 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Once, OnceLock};
+
 use libc::termios;
 
+// terminal attributes found at startup, restored whenever the terminal is handed back to the user
+static ORIGINAL_TERM: OnceLock<termios> = OnceLock::new();
+// whether the program currently owns the terminal in raw mode, which has to be re-applied after a stop
+static RAW_TERM: AtomicBool = AtomicBool::new(false);
+static TERM_HANDLERS: Once = Once::new();
+// set by SIGINT, the vm stops at the next instruction boundary when it sees it
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+// whether the user is typing a command, when SIGINT is not meant for the vm
+static PROMPTING: AtomicBool = AtomicBool::new(false);
+
 #[derive(Debug)]
 pub struct IoError(pub std::io::Error);
 
@@ -7,19 +23,113 @@ fn last_io_error() -> IoError {
     IoError(std::io::Error::last_os_error())
 }
 
-pub fn term_setup() -> Result<(), IoError> {
+// only async-signal-safe calls from here on, these run in signal handlers
+fn set_term(term: &termios) -> libc::c_int {
+    unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, term as *const termios) }
+}
+
+fn set_raw_term(original: &termios) -> libc::c_int {
     // remove canonical mode for stdin in order to disable buffering and make symbols accessible immediately
+    let mut term = *original;
+    term.c_lflag &= !libc::ICANON & !libc::ECHO;
+    set_term(&term)
+}
+
+fn restore_original_term() {
+    if let Some(term) = ORIGINAL_TERM.get() {
+        set_term(term);
+    }
+}
+
+extern "C" fn on_exit() {
+    restore_original_term();
+}
+
+// SIGTERM and SIGTSTP: hand the terminal back, then terminate or stop with the default action of the signal
+// once the handler returns
+extern "C" fn on_signal(signal: libc::c_int) {
+    restore_original_term();
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+// SIGINT: ask the vm to stop; when it did not get to it since the previous one, give up and die like on SIGTERM
+extern "C" fn on_interrupt(signal: libc::c_int) {
+    if PROMPTING.load(Ordering::SeqCst) {
+        return;
+    }
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        on_signal(signal);
+    }
+}
+
+extern "C" fn on_continue(_: libc::c_int) {
+    unsafe { libc::signal(libc::SIGTSTP, on_signal as *const () as libc::sighandler_t) };
+    if RAW_TERM.load(Ordering::SeqCst) {
+        if let Some(term) = ORIGINAL_TERM.get() {
+            set_raw_term(term);
+        }
+    }
+}
+
+// makes sure the terminal is usable again however the process ends or gets suspended
+fn install_term_handlers() {
+    unsafe {
+        libc::atexit(on_exit);
+        // no SA_RESTART, so that a blocking read returns and the vm gets to see the interrupt
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_interrupt as *const () as libc::sighandler_t;
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+        libc::signal(libc::SIGTERM, on_signal as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGTSTP, on_signal as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGCONT, on_continue as *const () as libc::sighandler_t);
+    }
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore_original_term();
+        hook(info)
+    }));
+}
+
+/// whether SIGINT arrived since the previous call
+pub fn take_interrupt() -> bool {
+    INTERRUPTED.swap(false, Ordering::SeqCst)
+}
+
+/// reads a line of user input from stdin; ctrl-c meanwhile neither stops the vm later nor kills the process
+pub fn read_line(line: &mut String) -> Result<usize, IoError> {
+    PROMPTING.store(true, Ordering::SeqCst);
+    let result = std::io::stdin().read_line(line).map_err(IoError);
+    PROMPTING.store(false, Ordering::SeqCst);
+    result
+}
+
+pub fn term_setup() -> Result<(), IoError> {
     let mut term: termios = termios { c_iflag: 0, c_oflag: 0, c_cflag: 0, c_lflag: 0, c_line: 0, c_cc: [0 as libc::cc_t; libc::NCCS], c_ispeed: 0, c_ospeed: 0 };
     if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut term as *mut termios) } != 0 {
         return Err(last_io_error());
     }
-    term.c_lflag &= !libc::ICANON & !libc::ECHO;
-    if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &term as *const termios) } != 0 {
+    let original = ORIGINAL_TERM.get_or_init(|| term);
+    TERM_HANDLERS.call_once(install_term_handlers);
+    RAW_TERM.store(true, Ordering::SeqCst);
+    if set_raw_term(original) != 0 {
         return Err(last_io_error());
     }
     Ok(())
 }
 
+pub fn term_restore() -> Result<(), IoError> {
+    RAW_TERM.store(false, Ordering::SeqCst);
+    if let Some(term) = ORIGINAL_TERM.get() {
+        if set_term(term) != 0 {
+            return Err(last_io_error());
+        }
+    }
+    Ok(())
+}
+
 pub fn getc() -> Result<u8, IoError> {
     let mut buf = [0u8];
     let result = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr() as *mut libc::c_void, 1) };
@@ -31,23 +141,35 @@ pub fn getc() -> Result<u8, IoError> {
 }
 
 pub fn putc(c: u8) -> Result<(), IoError> {
-    let buf = [c];
-    let result = unsafe { libc::write(libc::STDOUT_FILENO, buf.as_ptr() as *const libc::c_void, 1) };
-    if result < 0 {
-        return Err(last_io_error());
+    puts(&[c])
+}
+
+// waits until `fd` accepts more data, after a write to a non-blocking descriptor failed with EAGAIN
+fn wait_writable(fd: libc::c_int) -> Result<(), IoError> {
+    let mut poll_fd = libc::pollfd { fd, events: libc::POLLOUT, revents: 0 };
+    while unsafe { libc::poll(&mut poll_fd as *mut libc::pollfd, 1, -1) } < 0 {
+        let error = last_io_error();
+        if error.0.kind() != std::io::ErrorKind::Interrupted {
+            return Err(error);
+        }
     }
-    assert!(result == 1);
     Ok(())
 }
 
+/// writes all of `buf` to stdout, retrying partial, interrupted and would-block writes
 pub fn puts(buf: &[u8]) -> Result<(), IoError> {
     let mut current = buf;
     while !current.is_empty() {
-        let result = unsafe { libc::write(libc::STDIN_FILENO, buf.as_ptr() as *const libc::c_void, buf.len()) };
+        let result = unsafe { libc::write(libc::STDOUT_FILENO, current.as_ptr() as *const libc::c_void, current.len()) };
         if result < 0 {
-            return Err(last_io_error());
+            let error = last_io_error();
+            match error.0.kind() {
+                std::io::ErrorKind::Interrupted => {}
+                std::io::ErrorKind::WouldBlock => wait_writable(libc::STDOUT_FILENO)?,
+                _ => return Err(error),
+            }
+            continue;
         }
-        assert!(result > 0);
         current = &current[result as usize..];
     }
     Ok(())
@@ -64,3 +186,285 @@ pub fn hasc() -> Result<bool, IoError> {
         }
     })
 }
+
+/// character device behind the keyboard and display registers and the i/o traps
+pub trait Console {
+    /// next input character, waiting for one if there is none
+    fn getc(&mut self) -> Result<u8, IoError>;
+    fn putc(&mut self, c: u8) -> Result<(), IoError> {
+        self.puts(&[c])
+    }
+    fn puts(&mut self, buf: &[u8]) -> Result<(), IoError>;
+    /// whether `getc` would return without waiting
+    fn hasc(&mut self) -> Result<bool, IoError>;
+    /// like `hasc`, when the program itself looks at the keyboard status register
+    fn poll(&mut self) -> Result<bool, IoError> {
+        self.hasc()
+    }
+    /// writes out buffered output
+    fn flush(&mut self) -> Result<(), IoError> {
+        Ok(())
+    }
+}
+
+// output collected by `Stdio` before it is written out anyway
+const STDIO_BUFFER: usize = 1 << 14;
+// `hasc` calls after which `Stdio` writes out its output, for programs that wait for keyboard interrupts without polling
+const STDIO_HASC_FLUSH: u32 = 1 << 12;
+
+/// the process' stdin and stdout, usually a terminal set up by `term_setup`. output is buffered and written out before
+/// the program reads or polls for input, so everything it printed is visible by the time it waits for the user
+#[derive(Default)]
+pub struct Stdio {
+    output: Vec<u8>,
+    // `hasc` calls since the output was last written out
+    checks: u32,
+}
+
+impl Console for Stdio {
+    fn getc(&mut self) -> Result<u8, IoError> {
+        self.flush()?;
+        getc()
+    }
+    fn puts(&mut self, buf: &[u8]) -> Result<(), IoError> {
+        self.output.extend_from_slice(buf);
+        if self.output.len() >= STDIO_BUFFER {
+            self.flush()?;
+        }
+        Ok(())
+    }
+    // checked before every instruction while keyboard interrupts are enabled, so it only flushes once in a while
+    fn hasc(&mut self) -> Result<bool, IoError> {
+        self.checks += 1;
+        if self.checks >= STDIO_HASC_FLUSH {
+            self.flush()?;
+        }
+        hasc()
+    }
+    fn poll(&mut self) -> Result<bool, IoError> {
+        self.flush()?;
+        hasc()
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.checks = 0;
+        if !self.output.is_empty() {
+            puts(&self.output)?;
+            self.output.clear();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Stdio {
+    fn drop(&mut self) {
+        self.flush().unwrap_or(());
+    }
+}
+
+/// in-memory console: the program reads `input` and its output is appended to `output`
+#[derive(Default)]
+pub struct Buffers {
+    pub input:  VecDeque<u8>,
+    pub output: Vec<u8>,
+}
+
+impl Console for Buffers {
+    fn getc(&mut self) -> Result<u8, IoError> {
+        // nothing will ever arrive, so waiting would block forever
+        self.input.pop_front().ok_or_else(|| IoError(std::io::ErrorKind::UnexpectedEof.into()))
+    }
+    fn puts(&mut self, buf: &[u8]) -> Result<(), IoError> {
+        self.output.extend_from_slice(buf);
+        Ok(())
+    }
+    fn hasc(&mut self) -> Result<bool, IoError> {
+        Ok(!self.input.is_empty())
+    }
+}
+
+/// console over byte streams such as files, pipes or sockets; the input has to be backed by a file descriptor to tell
+/// whether data is available
+pub struct Streams<R: Read + AsRawFd, W: Write> {
+    pub input:  R,
+    pub output: W,
+}
+
+impl<R: Read + AsRawFd, W: Write> Console for Streams<R, W> {
+    fn getc(&mut self) -> Result<u8, IoError> {
+        self.flush()?;
+        let mut buf = [0u8];
+        self.input.read_exact(&mut buf).map_err(IoError)?;
+        Ok(buf[0])
+    }
+    fn puts(&mut self, buf: &[u8]) -> Result<(), IoError> {
+        self.output.write_all(buf).map_err(IoError)
+    }
+    fn hasc(&mut self) -> Result<bool, IoError> {
+        self.flush()?;
+        let mut n: libc::c_int = 0;
+        if unsafe { libc::ioctl(self.input.as_raw_fd(), libc::FIONREAD, &mut n as *mut libc::c_int) } < 0 {
+            return Err(last_io_error());
+        }
+        Ok(n > 0)
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.output.flush().map_err(IoError)
+    }
+}
+
+/// console for non-interactive runs. the script is typed key by key, `\{N}` delays the next key until `N` instructions
+/// (counted by `tick`) passed since the previous one was read and `\\` stands for a backslash; anything else, including
+/// a `\{` not followed by digits and `}`, is taken literally. output goes to `output`
+pub struct Script {
+    keys:       VecDeque<(u64, u8)>,
+    // instructions until the next key can be read
+    wait:       u64,
+    // the program polled the keyboard after the last key was read, it would wait forever
+    starved:    bool,
+    // instructions left for a program waiting for keyboard interrupts after the last key was read
+    idle:       Option<u64>,
+    pub output: Box<dyn Write>,
+}
+
+/// instructions a program waiting for keyboard interrupts may still run once the script is exhausted, to finish what it
+/// does with the last key; after that it is stopped like a program polling the keyboard
+pub const SCRIPT_IDLE_LIMIT: u64 = 1 << 20;
+
+fn script_exhausted() -> IoError {
+    IoError(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "input script exhausted"))
+}
+
+fn parse_delay(script: &[u8]) -> Option<(u64, usize)> {
+    let digits = script.iter().take_while(|c| c.is_ascii_digit()).count();
+    if digits == 0 || script.get(digits) != Some(&b'}') {
+        return None;
+    }
+    let delay = std::str::from_utf8(&script[..digits]).ok()?.parse().ok()?;
+    Some((delay, digits + 1))
+}
+
+impl Script {
+    pub fn new(script: &[u8], output: Box<dyn Write>) -> Self {
+        let mut keys = VecDeque::new();
+        let mut delay = 0u64;
+        let mut i = 0;
+        while i < script.len() {
+            if let Some((wait, length)) = script[i..].strip_prefix(b"\\{").and_then(parse_delay) {
+                delay = delay.saturating_add(wait);
+                i += 2 + length;
+                continue;
+            }
+            if script[i..].starts_with(b"\\\\") {
+                i += 1;
+            }
+            keys.push_back((delay, script[i]));
+            delay = 0;
+            i += 1;
+        }
+        let wait = keys.front().map_or(0, |&(delay, _)| delay);
+        Self { keys, wait, starved: false, idle: None, output }
+    }
+
+    /// advances the script by one executed instruction; fails once the program polled the keyboard with no keys left, or
+    /// waited for keyboard interrupts for `SCRIPT_IDLE_LIMIT` instructions with no keys left
+    pub fn tick(&mut self) -> Result<(), IoError> {
+        if self.starved || self.idle == Some(0) {
+            return Err(script_exhausted());
+        }
+        self.wait = self.wait.saturating_sub(1);
+        self.idle = self.idle.map(|idle| idle - 1);
+        Ok(())
+    }
+}
+
+impl Default for Script {
+    fn default() -> Self {
+        Self::new(b"", Box::new(std::io::sink()))
+    }
+}
+
+impl Console for Script {
+    fn getc(&mut self) -> Result<u8, IoError> {
+        // waiting for a delayed key simply skips the delay
+        let (_, c) = self.keys.pop_front().ok_or_else(script_exhausted)?;
+        self.wait = self.keys.front().map_or(0, |&(delay, _)| delay);
+        Ok(c)
+    }
+    fn puts(&mut self, buf: &[u8]) -> Result<(), IoError> {
+        self.output.write_all(buf).map_err(IoError)
+    }
+    fn hasc(&mut self) -> Result<bool, IoError> {
+        // without a poll, the program is looking for a key through the interrupt check
+        if self.keys.is_empty() && self.idle.is_none() {
+            self.idle = Some(SCRIPT_IDLE_LIMIT);
+        }
+        Ok(!self.keys.is_empty() && self.wait == 0)
+    }
+    fn poll(&mut self) -> Result<bool, IoError> {
+        self.starved |= self.keys.is_empty();
+        self.hasc()
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.output.flush().map_err(IoError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(text: &[u8]) -> Script {
+        Script::new(text, Box::new(std::io::sink()))
+    }
+
+    #[test]
+    fn parses_delays_and_escapes() {
+        let mut script = script(br"a\{2}b\\\{x}");
+        assert_eq!(script.getc().unwrap(), b'a');
+        assert!(!script.hasc().unwrap());
+        script.tick().unwrap();
+        assert!(!script.hasc().unwrap());
+        script.tick().unwrap();
+        assert!(script.hasc().unwrap());
+        let rest: Vec<u8> = std::iter::from_fn(|| script.getc().ok()).collect();
+        assert_eq!(rest, br"b\\{x}");
+    }
+
+    #[test]
+    fn stops_a_program_polling_past_the_end() {
+        let mut script = script(b"a");
+        assert!(script.poll().unwrap());
+        assert_eq!(script.getc().unwrap(), b'a');
+        script.tick().unwrap();
+        // interrupt checks may look at the keyboard without the program waiting for it
+        assert!(!script.hasc().unwrap());
+        script.tick().unwrap();
+        assert!(!script.poll().unwrap());
+        assert_eq!(script.tick().unwrap_err().0.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert!(script.getc().is_err());
+    }
+
+    #[test]
+    fn stops_a_program_waiting_for_interrupts_past_the_end() {
+        let mut script = script(b"a");
+        assert!(script.hasc().unwrap());
+        assert_eq!(script.getc().unwrap(), b'a');
+        assert!(!script.hasc().unwrap());
+        for _ in 0..SCRIPT_IDLE_LIMIT {
+            script.tick().unwrap();
+        }
+        assert_eq!(script.tick().unwrap_err().0.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn keeps_output_buffered_across_interrupt_checks() {
+        let mut stdio = Stdio::default();
+        stdio.puts(b"x").unwrap();
+        for _ in 1..STDIO_HASC_FLUSH {
+            stdio.hasc().ok();
+        }
+        assert_eq!(stdio.output, b"x");
+        // nothing is written to the test's stdout
+        stdio.output.clear();
+    }
+}
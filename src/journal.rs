@@ -0,0 +1,74 @@
+use std::cell::RefMut;
+
+use crate::io;
+use crate::ops::*;
+use crate::vm::{self, VmMem};
+
+#[derive(Clone, Copy)]
+pub enum Change {
+    Reg { register: Register, old: u16, new: u16 },
+    Mem { address: u16, old: u16, new: u16 },
+}
+
+/// memory wrapper that logs every register and memory write with the value it replaced, in order
+pub struct Journaled<V: VmMem> {
+    pub vm:  V,
+    changes: Vec<Change>,
+}
+
+impl<V: VmMem> Journaled<V> {
+    pub fn new(vm: V) -> Self {
+        Self { vm, changes: Vec::new() }
+    }
+    /// writes logged since the previous call
+    pub fn take_changes(&mut self) -> Vec<Change> {
+        std::mem::take(&mut self.changes)
+    }
+}
+
+impl<V: VmMem + Default> Default for Journaled<V> {
+    fn default() -> Self {
+        Self::new(V::default())
+    }
+}
+
+impl<V: VmMem> VmMem for Journaled<V> {
+    fn read_reg(&self, register: Register) -> u16 {
+        self.vm.read_reg(register)
+    }
+    fn write_reg(&mut self, register: Register, value: u16) {
+        self.changes.push(Change::Reg { register, old: self.vm.read_reg(register), new: value });
+        self.vm.write_reg(register, value)
+    }
+    fn read_mem(&self, address: u16) -> u16 {
+        self.vm.read_mem(address)
+    }
+    fn write_mem(&mut self, address: u16, value: u16) -> Result<(), io::IoError> {
+        self.changes.push(Change::Mem { address, old: self.vm.peek_mem(address), new: value });
+        self.vm.write_mem(address, value)
+    }
+    fn peek_mem(&self, address: u16) -> u16 {
+        self.vm.peek_mem(address)
+    }
+    fn console(&self) -> RefMut<'_, dyn io::Console + '_> {
+        self.vm.console()
+    }
+    fn poke_mem(&mut self, address: u16, value: u16) {
+        self.vm.poke_mem(address, value)
+    }
+    fn c_str(&self, address: u16) -> Vec<u8> {
+        self.vm.c_str(address)
+    }
+    fn packed_str(&self, address: u16) -> Vec<u8> {
+        self.vm.packed_str(address)
+    }
+    fn pending_interrupt(&self) -> Option<(u16, u16)> {
+        self.vm.pending_interrupt()
+    }
+    fn trap_dispatch(&self) -> vm::TrapDispatch {
+        self.vm.trap_dispatch()
+    }
+    fn input_log(&mut self) -> &mut vm::InputLog {
+        self.vm.input_log()
+    }
+}
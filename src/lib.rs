@@ -0,0 +1,15 @@
+pub mod asm;
+pub mod debug;
+pub mod debugger;
+pub mod disasm;
+pub mod gdb;
+pub mod history;
+pub mod io;
+pub mod journal;
+pub mod ops;
+pub mod ops_encode;
+pub mod ops_parse;
+pub mod trace;
+pub mod vm;
+pub mod vm_spec;
+pub mod watch;
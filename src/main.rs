@@ -1,18 +1,257 @@
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::io::{BufWriter, Write};
 use std::{env, fs};
 
-mod debug;
-mod ops;
-mod ops_parse;
-mod io;
-mod vm;
-mod vm_spec;
+use lc3_rust::vm::VmMem;
+use lc3_rust::vm_spec::VmSpec;
+use lc3_rust::{asm, debugger, disasm, gdb, io, journal, trace, vm, vm_spec, watch};
+
+fn read_obj(obj_path: &str) -> Vec<u16> {
+    let obj_bytes = fs::read(obj_path).unwrap_or_else(|e| panic!("object file '{}' not found: {}", obj_path, e));
+    assert!(obj_bytes.len().is_multiple_of(2), "object file must have even length: length('{}')={}", obj_path, obj_bytes.len());
+    obj_bytes.chunks_exact(2).map(|w| u16::from_be_bytes(w.try_into().unwrap())).collect()
+}
+
+fn read_symbols(sym_path: &str) -> disasm::Symbols {
+    disasm::parse_symbols(&fs::read_to_string(sym_path).unwrap_or_else(|e| panic!("symbol file '{}' not found: {}", sym_path, e)))
+}
+
+// the symbol table written next to the object by the assembler, if there is one
+fn sibling_symbols(obj_path: &str) -> Option<String> {
+    Some(Path::new(obj_path).with_extension("sym").to_string_lossy().into_owned()).filter(|p| Path::new(p).exists())
+}
+
+fn program_symbols(obj_paths: &[String]) -> disasm::Symbols {
+    obj_paths.iter().filter_map(|path| sibling_symbols(path)).flat_map(|path| read_symbols(&path)).collect()
+}
+
+fn parse_address(value: &str) -> u16 {
+    asm::parse_number(value).and_then(|v| u16::try_from(v).ok()).unwrap_or_else(|| panic!("invalid address '{}'", value))
+}
+
+// exit status after ctrl-c, as a shell reports a process killed by SIGINT
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+// a restored machine already holds its programs, objects given along with it only contribute their symbols
+fn start_vm<C: io::Console + Default>(objs: &[vm_spec::Object], restore_path: Option<&str>, console: C, pc: Option<u16>, trap_dispatch: vm::TrapDispatch) -> vm::Vm<C> {
+    let mut vm = match restore_path {
+        Some(path) => {
+            let snapshot = fs::read(path).unwrap_or_else(|e| panic!("snapshot file '{}' not found: {}", path, e));
+            vm::Vm::restore(&snapshot, console).unwrap_or_else(|e| panic!("unable to restore snapshot '{}': {}", path, e))
+        }
+        None => {
+            let mut vm: vm::Vm<C> = VmSpec::load(objs).unwrap_or_else(|e| panic!("unable to load vm: {}", e));
+            *vm.console_mut() = console;
+            vm
+        }
+    };
+    if let Some(pc) = pc {
+        vm.write_reg(vm_spec::R_PC, pc);
+    }
+    vm.set_trap_dispatch(trap_dispatch);
+    vm
+}
+
+// writes the state to the --save path, if there is one; every mode saves once it is done, even when the vm failed, to
+// be able to look into the failure later
+fn save_snapshot(vm: &vm::Vm<impl io::Console>, save_path: Option<&str>) {
+    let Some(save_path) = save_path else {
+        return;
+    };
+    let snapshot = vm.snapshot().unwrap_or_else(|e| panic!("unable to take snapshot: {}", e));
+    fs::write(save_path, snapshot).unwrap_or_else(|e| panic!("unable to write snapshot '{}': {}", save_path, e));
+}
+
+// runs without a terminal: keys come from the script, each instruction advances its clock
+fn run_headless(vm: &mut vm::Vm<io::Script>) -> Result<(), vm_spec::TickError> {
+    while vm.tick()? {
+        vm.console_mut().tick().map_err(vm_spec::TickError::Io)?;
+    }
+    Ok(())
+}
+
+// ctrl-c reports where the program stopped (or enters the debugger with --debug-on-interrupt), other failures panic
+fn finish(vm: vm::Vm, result: Result<(), vm_spec::TickError>, obj_paths: &[String], debug_on_interrupt: bool) {
+    match result {
+        Err(vm_spec::TickError::Interrupted) if debug_on_interrupt => {
+            let mut vm = watch::Watched::new(journal::Journaled::new(vm));
+            eprintln!("interrupted");
+            debugger::Debugger::new(program_symbols(obj_paths), &mut vm).run(&mut vm).unwrap_or_else(|e| panic!("debugger failed: {}", e))
+        }
+        Err(vm_spec::TickError::Interrupted) => {
+            vm.console().flush().unwrap_or(());
+            io::term_restore().unwrap_or(());
+            let pc = vm.read_reg(vm_spec::R_PC);
+            let code = vm.peek_mem(pc);
+            eprintln!("\ninterrupted at x{:04X}  x{:04X}  {}", pc, code, disasm::format_word(code, pc, &program_symbols(obj_paths)));
+            eprintln!("{}", debugger::format_regs(&vm));
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        result => result.unwrap_or_else(|e| panic!("vm failed: {}", e)),
+    }
+}
+
+fn assemble(mut args: impl Iterator<Item = String>) {
+    let mut src_path = None;
+    let mut obj_path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => obj_path = Some(args.next().unwrap_or_else(|| panic!("output path must be provided after -o"))),
+            _ => src_path = Some(arg),
+        }
+    }
+    let src_path = src_path.unwrap_or_else(|| panic!("source path must be provided as an argument"));
+    let obj_path = obj_path.unwrap_or_else(|| Path::new(&src_path).with_extension("obj").to_string_lossy().into_owned());
+    let source = fs::read_to_string(&src_path).unwrap_or_else(|e| panic!("source file '{}' not found: {}", src_path, e));
+    let program = asm::assemble(&source).unwrap_or_else(|e| panic!("{}: {}", src_path, e));
+    let obj_bytes: Vec<u8> = program.obj.iter().flat_map(|w| w.to_be_bytes()).collect();
+    fs::write(&obj_path, obj_bytes).unwrap_or_else(|e| panic!("unable to write '{}': {}", obj_path, e));
+    let sym_path = Path::new(&obj_path).with_extension("sym");
+    fs::write(&sym_path, asm::symbol_table(&program)).unwrap_or_else(|e| panic!("unable to write '{}': {}", sym_path.display(), e));
+}
+
+fn disassemble(mut args: impl Iterator<Item = String>) {
+    let mut obj_path = None;
+    let mut sym_path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--sym" => sym_path = Some(args.next().unwrap_or_else(|| panic!("symbol table path must be provided after --sym"))),
+            _ => obj_path = Some(arg),
+        }
+    }
+    let obj_path = obj_path.unwrap_or_else(|| panic!("object path must be provided as an argument"));
+    let symbols = match sym_path.or_else(|| sibling_symbols(&obj_path)) {
+        Some(sym_path) => read_symbols(&sym_path),
+        None => disasm::Symbols::new(),
+    };
+    print!("{}", disasm::disassemble(&read_obj(&obj_path), &symbols));
+}
 
 fn main() {
+    let mut args = env::args().skip(1).peekable();
+    match args.peek().map(String::as_str) {
+        Some("asm") => return assemble(args.skip(1)),
+        Some("disasm") => return disassemble(args.skip(1)),
+        _ => {}
+    }
+    let mut obj_paths = Vec::new();
+    let mut os_paths = Vec::new();
+    let mut pc = None;
+    let mut trap_dispatch = vm::TrapDispatch::Subroutine;
+    let mut debug = false;
+    let mut gdb_address = None;
+    let mut trace_path = None;
+    let mut trace_format = trace::TraceFormat::JsonLines;
+    let mut restore_path = None;
+    let mut save_path = None;
+    let mut headless = false;
+    let mut debug_on_interrupt = false;
+    let mut input = None;
+    let mut output_path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--os" => os_paths.push(args.next().unwrap_or_else(|| panic!("os image path must be provided after --os"))),
+            "--pc" => pc = Some(parse_address(&args.next().unwrap_or_else(|| panic!("start address must be provided after --pc")))),
+            "--rti-traps" => trap_dispatch = vm::TrapDispatch::Supervisor,
+            "--debug" => debug = true,
+            "--gdb" => gdb_address = Some(args.next().unwrap_or_else(|| panic!("port or socket path must be provided after --gdb"))),
+            "--trace" => trace_path = Some(args.next().unwrap_or_else(|| panic!("trace file path must be provided after --trace"))),
+            "--trace-format" => {
+                trace_format = match args.next().as_deref() {
+                    Some("jsonl") => trace::TraceFormat::JsonLines,
+                    Some("bin") => trace::TraceFormat::Binary,
+                    other => panic!("trace format must be 'jsonl' or 'bin', got {:?}", other),
+                }
+            }
+            "--restore" => restore_path = Some(args.next().unwrap_or_else(|| panic!("snapshot path must be provided after --restore"))),
+            "--save" => save_path = Some(args.next().unwrap_or_else(|| panic!("snapshot path must be provided after --save"))),
+            "--headless" => headless = true,
+            "--debug-on-interrupt" => debug_on_interrupt = true,
+            "--input" => input = Some(args.next().unwrap_or_else(|| panic!("input file or string must be provided after --input"))),
+            "--output" => output_path = Some(args.next().unwrap_or_else(|| panic!("output file path must be provided after --output"))),
+            _ => obj_paths.push(arg),
+        }
+    }
+    assert!(!obj_paths.is_empty() || restore_path.is_some(), "object path must be provided as an argument");
+    assert!([debug, gdb_address.is_some(), trace_path.is_some()].iter().filter(|&&mode| mode).count() <= 1, "--debug, --gdb and --trace cannot be combined");
+    assert!(gdb_address.is_none() || !debug_on_interrupt, "--debug-on-interrupt cannot be combined with --gdb");
+    // os images are placed after the program objects so that the first program object defines the start address
+    obj_paths.append(&mut os_paths);
+    let images: Vec<Vec<u16>> = obj_paths.iter().map(|path| read_obj(path)).collect();
+    let objs: Vec<vm_spec::Object> = obj_paths.iter().zip(&images).map(|(name, obj)| vm_spec::Object { name, obj }).collect();
+    if headless || input.is_some() || output_path.is_some() {
+        assert!(!debug && gdb_address.is_none() && trace_path.is_none() && !debug_on_interrupt, "--debug, --gdb, --trace and --debug-on-interrupt cannot be combined with headless mode");
+        // an existing file provides the script, anything else is the script itself
+        let script = match &input {
+            Some(input) if Path::new(input).is_file() => fs::read(input).unwrap_or_else(|e| panic!("unable to read input file '{}': {}", input, e)),
+            Some(input) => input.as_bytes().to_vec(),
+            None => Vec::new(),
+        };
+        let output: Box<dyn Write> = match &output_path {
+            Some(path) => Box::new(BufWriter::new(fs::File::create(path).unwrap_or_else(|e| panic!("unable to create output file '{}': {}", path, e)))),
+            None => Box::new(std::io::stdout()),
+        };
+        let mut vm = start_vm(&objs, restore_path.as_deref(), io::Script::new(&script, output), pc, trap_dispatch);
+        let result = run_headless(&mut vm);
+        // whatever the program printed is written out before a failure is reported
+        vm.console_mut().output.flush().unwrap_or_else(|e| panic!("unable to write output: {}", e));
+        save_snapshot(&vm, save_path.as_deref());
+        return result.unwrap_or_else(|e| panic!("vm failed: {}", e));
+    }
+    let mut vm: vm::Vm = start_vm(&objs, restore_path.as_deref(), io::Stdio::default(), pc, trap_dispatch);
+    // the gdb client drives the program, so the terminal (if there is one at all) is left as it is
+    if let Some(address) = gdb_address {
+        // a plain number is a tcp port on the loopback interface, anything else a unix socket path
+        let result = match address.parse::<u16>() {
+            Ok(port) => {
+                let listener = TcpListener::bind(("127.0.0.1", port)).unwrap_or_else(|e| panic!("unable to listen on port {}: {}", port, e));
+                eprintln!("waiting for gdb on 127.0.0.1:{}", port);
+                let (stream, _) = listener.accept().unwrap_or_else(|e| panic!("unable to accept gdb connection: {}", e));
+                gdb::GdbStub::new(stream).serve(&mut vm)
+            }
+            Err(_) => {
+                let listener = UnixListener::bind(&address).unwrap_or_else(|e| panic!("unable to listen on '{}': {}", address, e));
+                eprintln!("waiting for gdb on {}", address);
+                let (stream, _) = listener.accept().unwrap_or_else(|e| panic!("unable to accept gdb connection: {}", e));
+                gdb::GdbStub::new(stream).serve(&mut vm)
+            }
+        };
+        save_snapshot(&vm, save_path.as_deref());
+        return result.unwrap_or_else(|e| panic!("gdb stub failed: {}", e));
+    }
     io::term_setup().unwrap_or_else(|e| panic!("terminal setup failed: {}", e));
-    let obj_path = &env::args().nth(1).unwrap_or_else(|| panic!("object path must be provided as first argument"));
-    let obj_bytes = fs::read(obj_path).unwrap_or_else(|e| panic!("object file '{}' not found: {}", obj_path, e));
-    assert!(obj_bytes.len() % 2 == 0, "object file must have even length: length('{}')={}", obj_path, obj_bytes.len());
-    let obj_values: Vec<u16> = obj_bytes.chunks_exact(2).map(|w| u16::from_be_bytes(w.try_into().unwrap())).collect();
-    let mut vm: vm::Vm = vm_spec::VmSpec::load(&obj_values).unwrap_or_else(|e| panic!("unable to load vm: {}", e));
-    vm_spec::run(&mut vm).unwrap_or_else(|e| panic!("vm failed: {}", e));
+    if debug {
+        let mut vm = watch::Watched::new(journal::Journaled::new(vm));
+        let result = debugger::Debugger::new(program_symbols(&obj_paths), &mut vm).run(&mut vm);
+        save_snapshot(&vm.vm.vm, save_path.as_deref());
+        return result.unwrap_or_else(|e| panic!("debugger failed: {}", e));
+    }
+    if let Some(trace_path) = trace_path {
+        let file = fs::File::create(&trace_path).unwrap_or_else(|e| panic!("unable to create trace file '{}': {}", trace_path, e));
+        let mut tracer = trace::Tracer::new(BufWriter::new(file), trace_format).unwrap_or_else(|e| panic!("unable to write trace file '{}': {}", trace_path, e));
+        let mut vm = journal::Journaled::new(vm);
+        let result = trace::run(&mut vm, &mut tracer);
+        save_snapshot(&vm.vm, save_path.as_deref());
+        return finish(vm.vm, result, &obj_paths, debug_on_interrupt);
+    }
+    let result = vm_spec::run(&mut vm);
+    save_snapshot(&vm, save_path.as_deref());
+    finish(vm, result, &obj_paths, debug_on_interrupt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_at_the_given_pc() {
+        let objs = [vm_spec::Object { name: "test", obj: &[0x3000, 0xf025, 0xf025] }];
+        let vm = start_vm(&objs, None, io::Buffers::default(), None, vm::TrapDispatch::Subroutine);
+        assert_eq!(vm.read_reg(vm_spec::R_PC), 0x3000);
+        let vm = start_vm(&objs, None, io::Buffers::default(), Some(0x3001), vm::TrapDispatch::Subroutine);
+        assert_eq!(vm.read_reg(vm_spec::R_PC), 0x3001);
+        assert_eq!(vm.peek_mem(0x3000), 0xf025);
+    }
 }
@@ -1,13 +1,13 @@
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct Register(pub usize);
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum Argument {
     Register(Register),
     Immediate(u16),
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum Operation {
     Add { dr: Register, sr1: Register, arg: Argument },  /* add  */
     And { dr: Register, sr1: Register, arg: Argument },  /* bitwise and */
@@ -24,6 +24,6 @@ pub enum Operation {
     Sti { sr: Register, pc_offset: u16 },                /* store indirect */
     Str { sr: Register, base_r: Register, offset: u16 }, /* store register */
     Trap { trap_vector: u16 },                           /* execute trap */
-    Rti,                                                 /* unused */
+    Rti,                                                 /* return from interrupt */
 }
 
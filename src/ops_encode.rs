@@ -0,0 +1,205 @@
+use crate::ops;
+
+pub struct Encoder {
+    pub code:     u16,
+    pub position: i32,
+}
+
+#[derive(Debug)]
+pub enum EncodeError {
+    RegisterOutOfRange { register: usize },
+    UnsignedOutOfRange { value: u16, bit_size: i32 },
+    SignedOutOfRange { value: u16, bit_size: i32 },
+}
+
+impl Encoder {
+    pub fn unsigned(&mut self, bit_size: i32, value: u16) -> Result<(), EncodeError> {
+        if self.position - bit_size < 0 {
+            panic!("u16 op writer overflow: code={}, written_bits={}", self.code, 16 - self.position + bit_size);
+        }
+        if value >> bit_size != 0 {
+            return Err(EncodeError::UnsignedOutOfRange { value, bit_size });
+        }
+        self.position -= bit_size;
+        self.code |= value << self.position;
+        Ok(())
+    }
+    pub fn fixed(&mut self, bit_size: i32, value: u16) {
+        self.unsigned(bit_size, value).expect("fixed segment must fit its size")
+    }
+    pub fn register(&mut self, register: ops::Register) -> Result<(), EncodeError> {
+        if register.0 >= 8 {
+            return Err(EncodeError::RegisterOutOfRange { register: register.0 });
+        }
+        self.unsigned(3, register.0 as u16)
+    }
+
+    pub fn signed(&mut self, bit_size: i32, value: u16) -> Result<(), EncodeError> {
+        // value must be the sign extension of its lowest bit_size bits
+        let min = -(1i32 << (bit_size - 1));
+        if !(min..-min).contains(&(value as i16 as i32)) {
+            return Err(EncodeError::SignedOutOfRange { value, bit_size });
+        }
+        self.unsigned(bit_size, value & ((1 << bit_size) - 1))
+    }
+    pub fn argument(&mut self, arg: ops::Argument) -> Result<(), EncodeError> {
+        match arg {
+            ops::Argument::Immediate(imm) => {
+                self.fixed(1, 1);
+                self.signed(5, imm)
+            }
+            ops::Argument::Register(r) => {
+                self.fixed(3, 0b000);
+                self.register(r)
+            }
+        }
+    }
+}
+
+impl ops::Operation {
+    /// inverse of `Operation::parse`: packs the operation into a machine word, validating register and field ranges
+    pub fn encode(&self) -> Result<u16, EncodeError> {
+        let mut encoder = Encoder { code: 0, position: 16 };
+        match *self {
+            ops::Operation::Add { dr, sr1, arg } => {
+                encoder.fixed(4, 0b0001);
+                encoder.register(dr)?;
+                encoder.register(sr1)?;
+                encoder.argument(arg)?;
+            }
+            ops::Operation::And { dr, sr1, arg } => {
+                encoder.fixed(4, 0b0101);
+                encoder.register(dr)?;
+                encoder.register(sr1)?;
+                encoder.argument(arg)?;
+            }
+            ops::Operation::Br { n, z, p, pc_offset } => {
+                encoder.fixed(4, 0b0000);
+                encoder.fixed(1, n as u16);
+                encoder.fixed(1, z as u16);
+                encoder.fixed(1, p as u16);
+                encoder.signed(9, pc_offset)?;
+            }
+            ops::Operation::Jmp { base_r } => {
+                encoder.fixed(4, 0b1100);
+                encoder.fixed(3, 0);
+                encoder.register(base_r)?;
+                encoder.fixed(6, 0);
+            }
+            ops::Operation::Jsr { pc_offset } => {
+                encoder.fixed(4, 0b0100);
+                encoder.fixed(1, 1);
+                encoder.signed(11, pc_offset)?;
+            }
+            ops::Operation::Jsrr { base_r } => {
+                encoder.fixed(4, 0b0100);
+                encoder.fixed(3, 0);
+                encoder.register(base_r)?;
+                encoder.fixed(6, 0);
+            }
+            ops::Operation::Ld { dr, pc_offset } => {
+                encoder.fixed(4, 0b0010);
+                encoder.register(dr)?;
+                encoder.signed(9, pc_offset)?;
+            }
+            ops::Operation::Ldi { dr, pc_offset } => {
+                encoder.fixed(4, 0b1010);
+                encoder.register(dr)?;
+                encoder.signed(9, pc_offset)?;
+            }
+            ops::Operation::Ldr { dr, base_r, offset } => {
+                encoder.fixed(4, 0b0110);
+                encoder.register(dr)?;
+                encoder.register(base_r)?;
+                encoder.signed(6, offset)?;
+            }
+            ops::Operation::Lea { dr, pc_offset } => {
+                encoder.fixed(4, 0b1110);
+                encoder.register(dr)?;
+                encoder.signed(9, pc_offset)?;
+            }
+            ops::Operation::Not { dr, sr } => {
+                encoder.fixed(4, 0b1001);
+                encoder.register(dr)?;
+                encoder.register(sr)?;
+                encoder.fixed(6, 0b111111);
+            }
+            ops::Operation::Rti => {
+                encoder.fixed(4, 0b1000);
+                encoder.fixed(12, 0);
+            }
+            ops::Operation::St { sr, pc_offset } => {
+                encoder.fixed(4, 0b0011);
+                encoder.register(sr)?;
+                encoder.signed(9, pc_offset)?;
+            }
+            ops::Operation::Sti { sr, pc_offset } => {
+                encoder.fixed(4, 0b1011);
+                encoder.register(sr)?;
+                encoder.signed(9, pc_offset)?;
+            }
+            ops::Operation::Str { sr, base_r, offset } => {
+                encoder.fixed(4, 0b0111);
+                encoder.register(sr)?;
+                encoder.register(base_r)?;
+                encoder.signed(6, offset)?;
+            }
+            ops::Operation::Trap { trap_vector } => {
+                encoder.fixed(4, 0b1111);
+                encoder.fixed(4, 0);
+                encoder.unsigned(8, trap_vector)?;
+            }
+        }
+        Ok(encoder.code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ops::{Argument, Operation, Register};
+
+    #[test]
+    fn encodes_every_decodable_word_back() {
+        let mut decodable = 0;
+        for code in 0..=u16::MAX {
+            if let Ok(op) = Operation::parse(code) {
+                let encoded = op.encode().unwrap_or_else(|e| panic!("{:?} from x{:04X} does not encode: {}", op, code, e));
+                assert_eq!(Operation::parse(encoded).ok(), Some(op), "x{:04X} encodes to x{:04X}", code, encoded);
+                assert_eq!(encoded, code);
+                decodable += 1;
+            }
+        }
+        assert_eq!(decodable, 40273);
+    }
+
+    #[test]
+    fn rejects_registers_out_of_range() {
+        let op = Operation::Not { dr: Register(8), sr: Register(0) };
+        assert!(matches!(op.encode(), Err(EncodeError::RegisterOutOfRange { register: 8 })));
+        let op = Operation::Add { dr: Register(0), sr1: Register(1), arg: Argument::Register(Register(9)) };
+        assert!(matches!(op.encode(), Err(EncodeError::RegisterOutOfRange { register: 9 })));
+    }
+
+    #[test]
+    fn rejects_immediates_out_of_range() {
+        let op = |imm: i16| Operation::And { dr: Register(0), sr1: Register(0), arg: Argument::Immediate(imm as u16) };
+        assert_eq!(op(15).encode().unwrap(), 0x502f);
+        assert_eq!(op(-16).encode().unwrap(), 0x5030);
+        assert!(matches!(op(16).encode(), Err(EncodeError::SignedOutOfRange { value: 16, bit_size: 5 })));
+        assert!(matches!(op(-17).encode(), Err(EncodeError::SignedOutOfRange { bit_size: 5, .. })));
+        assert!(matches!(Operation::Trap { trap_vector: 0x100 }.encode(), Err(EncodeError::UnsignedOutOfRange { value: 0x100, bit_size: 8 })));
+    }
+
+    #[test]
+    fn rejects_offsets_out_of_range() {
+        let br = |pc_offset: i16| Operation::Br { n: true, z: true, p: true, pc_offset: pc_offset as u16 };
+        assert_eq!(br(255).encode().unwrap(), 0x0eff);
+        assert_eq!(br(-256).encode().unwrap(), 0x0f00);
+        assert!(matches!(br(256).encode(), Err(EncodeError::SignedOutOfRange { bit_size: 9, .. })));
+        assert!(matches!(br(-257).encode(), Err(EncodeError::SignedOutOfRange { bit_size: 9, .. })));
+        assert!(matches!(Operation::Jsr { pc_offset: 1024 }.encode(), Err(EncodeError::SignedOutOfRange { bit_size: 11, .. })));
+        let ldr = Operation::Ldr { dr: Register(0), base_r: Register(1), offset: 32 };
+        assert!(matches!(ldr.encode(), Err(EncodeError::SignedOutOfRange { bit_size: 6, .. })));
+    }
+}
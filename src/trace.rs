@@ -0,0 +1,173 @@
+use std::io::Write;
+
+use crate::io;
+use crate::journal::{Change, Journaled};
+use crate::ops::*;
+use crate::vm::VmMem;
+use crate::vm_spec::{TickError, VmSpec, R_PC, R_PSR};
+
+const BINARY_MAGIC: &[u8; 4] = b"LC3T";
+const BINARY_VERSION: u16 = 1;
+
+#[derive(Clone, Copy)]
+pub enum TraceFormat {
+    /// one json object per executed instruction
+    JsonLines,
+    /// `LC3T` magic and u16 version, then per instruction (all big-endian):
+    /// pc u16, word u16, cc u8 (n=4, z=2, p=1), register count u8, memory count u8,
+    /// registers as (index u8, value u16) and memory writes as (address u16, value u16)
+    Binary,
+}
+
+/// effects of a single executed instruction
+pub struct TraceEntry {
+    pub pc:        u16,
+    pub word:      u16,
+    pub registers: Vec<(Register, u16)>,
+    pub memory:    Vec<(u16, u16)>,
+    pub cond:      u16,
+}
+
+pub struct Tracer<W: Write> {
+    out:    W,
+    format: TraceFormat,
+}
+
+impl TraceEntry {
+    // every written register and memory word is reported once with its final value, also when the write kept it as it
+    // was (e.g. ADD R1, R1, #0), and for device registers with what they kept of the write; pc is implied by the next
+    // entry and condition code updates of PSR show in cc
+    fn from_changes(pc: u16, word: u16, changes: &[Change], vm_mem: &impl VmMem) -> Self {
+        let mut registers: Vec<(Register, u16)> = Vec::new();
+        let mut memory = Vec::new();
+        for change in changes {
+            match *change {
+                Change::Reg { register, .. } if register == R_PC || registers.iter().any(|(r, _)| *r == register) => {}
+                Change::Reg { register, old, new } if register == R_PSR && (old ^ new) & !0b111 == 0 => {}
+                Change::Reg { register, .. } => registers.push((register, vm_mem.read_reg(register))),
+                Change::Mem { address, .. } if memory.iter().any(|(a, _)| *a == address) => {}
+                Change::Mem { address, .. } => memory.push((address, vm_mem.peek_mem(address))),
+            }
+        }
+        Self { pc, word, registers, memory, cond: vm_mem.read_reg(R_PSR) & 0b111 }
+    }
+}
+
+fn cond_flags(cond: u16) -> String {
+    [(4, 'n'), (2, 'z'), (1, 'p')].iter().filter(|&&(bit, _)| cond & bit != 0).map(|&(_, c)| c).collect()
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(mut out: W, format: TraceFormat) -> std::io::Result<Self> {
+        if let TraceFormat::Binary = format {
+            out.write_all(BINARY_MAGIC)?;
+            out.write_all(&BINARY_VERSION.to_be_bytes())?;
+        }
+        Ok(Self { out, format })
+    }
+
+    pub fn record(&mut self, entry: &TraceEntry) -> std::io::Result<()> {
+        match self.format {
+            TraceFormat::JsonLines => {
+                let op = match Operation::parse(entry.word) {
+                    Ok(op) => format!("{:?}", op),
+                    Err(_) => "illegal".to_string(),
+                };
+                let registers: Vec<String> = entry.registers.iter().map(|(r, value)| format!("{{\"reg\":\"{:?}\",\"value\":{}}}", r, value)).collect();
+                let memory: Vec<String> = entry.memory.iter().map(|(address, value)| format!("{{\"addr\":{},\"value\":{}}}", address, value)).collect();
+                writeln!(self.out, "{{\"pc\":{},\"word\":{},\"op\":\"{}\",\"regs\":[{}],\"mem\":[{}],\"cc\":\"{}\"}}", entry.pc, entry.word, op, registers.join(","), memory.join(","), cond_flags(entry.cond))
+            }
+            TraceFormat::Binary => {
+                let mut record = Vec::new();
+                record.extend(entry.pc.to_be_bytes());
+                record.extend(entry.word.to_be_bytes());
+                record.extend([entry.cond as u8, entry.registers.len() as u8, entry.memory.len() as u8]);
+                for (r, value) in &entry.registers {
+                    record.push(r.0 as u8);
+                    record.extend(value.to_be_bytes());
+                }
+                for (address, value) in &entry.memory {
+                    record.extend(address.to_be_bytes());
+                    record.extend(value.to_be_bytes());
+                }
+                self.out.write_all(&record)
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// executes a single instruction and records it
+pub fn tick_traced<V: VmMem + Default>(vm: &mut Journaled<V>, tracer: &mut Tracer<impl Write>) -> Result<bool, TickError> {
+    vm.take_changes();
+    let pc = vm.read_reg(R_PC);
+    let word = vm.peek_mem(pc);
+    let running = vm.tick();
+    let entry = TraceEntry::from_changes(pc, word, &vm.take_changes(), &vm.vm);
+    tracer.record(&entry).map_err(|e| TickError::Io(io::IoError(e)))?;
+    running
+}
+
+/// like `vm_spec::run`, recording every executed instruction
+pub fn run<V: VmMem + Default>(vm: &mut Journaled<V>, tracer: &mut Tracer<impl Write>) -> Result<(), TickError> {
+    let result = loop {
+        if io::take_interrupt() {
+            break Err(TickError::Interrupted);
+        }
+        match tick_traced(vm, tracer) {
+            Ok(true) => continue,
+            Ok(false) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+    tracer.flush().map_err(|e| TickError::Io(io::IoError(e)))?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::Vm;
+    use crate::vm_spec::Object;
+
+    fn trace(program: &[u16], format: TraceFormat) -> Vec<u8> {
+        let vm: Vm<io::Buffers> = VmSpec::load(&[Object { name: "test", obj: program }]).unwrap();
+        let mut tracer = Tracer::new(Vec::new(), format).unwrap();
+        run(&mut Journaled::new(vm), &mut tracer).unwrap();
+        tracer.out
+    }
+
+    #[test]
+    fn reports_register_writes_that_keep_the_value() {
+        // ADD R1, R1, #0; ST R1, #1; HALT
+        let trace = String::from_utf8(trace(&[0x3000, 0x1260, 0x3201, 0xf025, 0x0000], TraceFormat::JsonLines)).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with(r#"{"pc":12288,"word":4704,"#));
+        assert!(lines[0].ends_with(r#""regs":[{"reg":"R1","value":0}],"mem":[],"cc":"z"}"#));
+        assert!(lines[1].ends_with(r#""regs":[],"mem":[{"addr":12291,"value":0}],"cc":"z"}"#));
+        // the built-in HALT still points R7 behind it
+        assert!(lines[2].ends_with(r#""regs":[{"reg":"R7","value":12291}],"mem":[],"cc":"z"}"#));
+    }
+
+    #[test]
+    fn reports_the_values_device_registers_kept() {
+        // LD R0, #3; STI R0, #3; STI R0, #3; HALT; xFFFF; KBSR; KBDR
+        let trace = String::from_utf8(trace(&[0x3000, 0x2003, 0xb003, 0xb003, 0xf025, 0xffff, 0xfe00, 0xfe02], TraceFormat::JsonLines)).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert!(lines[1].contains(r#""mem":[{"addr":65024,"value":16384}]"#));
+        assert!(lines[2].contains(r#""mem":[{"addr":65026,"value":0}]"#));
+    }
+
+    #[test]
+    fn writes_binary_records() {
+        // ADD R1, R1, #2; HALT
+        let mut expected = b"LC3T\x00\x01".to_vec();
+        expected.extend([0x30, 0x00, 0x12, 0x62, 1, 1, 0, 1, 0x00, 0x02]);
+        expected.extend([0x30, 0x01, 0xf0, 0x25, 1, 1, 0, 7, 0x30, 0x02]);
+        assert_eq!(trace(&[0x3000, 0x1262, 0xf025], TraceFormat::Binary), expected);
+    }
+}
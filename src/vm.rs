@@ -1,23 +1,161 @@
+use std::cell::{RefCell, RefMut};
+use std::collections::VecDeque;
+
 use crate::io;
 use crate::ops::*;
 
 pub const MEMORY_MAX: usize = 1 << 16;
-pub const REGISTERS: usize = 10;
+pub const REGISTERS: usize = 12;
+
+pub const KBSR: u16 = 0xfe00;
+pub const KBDR: u16 = 0xfe02;
+pub const DSR: u16 = 0xfe04;
+pub const DDR: u16 = 0xfe06;
+pub const MCR: u16 = 0xfffe;
+
+pub const KBSR_READY: u16 = 1 << 15;
+pub const KBSR_IE: u16 = 1 << 14;
+pub const DSR_READY: u16 = 1 << 15;
+pub const MCR_CLOCK_ENABLE: u16 = 1 << 15;
+pub const KBD_INTERRUPT_VECTOR: u16 = 0x80;
+pub const KBD_INTERRUPT_PRIORITY: u16 = 4;
+
+const SNAPSHOT_MAGIC: &[u8; 4] = b"LC3S";
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+/// how TRAP enters a routine installed in the trap vector table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TrapDispatch {
+    /// jumps to the routine with the return address in R7, routines end with RET (as in lc3os.obj)
+    #[default]
+    Subroutine,
+    /// enters the routine in supervisor mode like an exception handler, routines end with RTI
+    Supervisor,
+}
+
+pub struct Vm<C: io::Console = io::Stdio> {
+    memory:        [u16; MEMORY_MAX],
+    registers:     [u16; REGISTERS],
+    console:       RefCell<Pending<C>>,
+    trap_dispatch: TrapDispatch,
+}
+
+/// answer the console gave the program: whether a key was ready, or the key read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    Ready(bool),
+    Char(u8),
+}
+
+/// what the program learned from the console, kept to run the same instructions again
+#[derive(Default)]
+pub struct InputLog {
+    /// whether answers are appended to `recorded`
+    pub recording: bool,
+    pub recorded:  Vec<Input>,
+    /// answers given in place of the console's while replaying; output is dropped meanwhile
+    pub replay:    Option<VecDeque<Input>>,
+}
+
+impl InputLog {
+    // next replayed answer, if it is of the kind the program asks for
+    fn replayed(&mut self, matches: impl Fn(Input) -> bool) -> Option<Input> {
+        let replay = self.replay.as_mut()?;
+        replay.front().copied().filter(|&input| matches(input)).inspect(|_| {
+            replay.pop_front();
+        })
+    }
+    fn record(&mut self, input: Input) {
+        if self.recording {
+            self.recorded.push(input);
+        }
+    }
+}
+
+// console of the vm with the characters taken from it (or a snapshot) that the program has not read yet in front
+struct Pending<C: io::Console> {
+    input:   VecDeque<u8>,
+    console: C,
+    log:     InputLog,
+}
+
+impl<C: io::Console> Pending<C> {
+    fn ready(&mut self, ready: impl FnOnce(&mut C) -> Result<bool, io::IoError>) -> Result<bool, io::IoError> {
+        let ready = match self.log.replayed(|input| matches!(input, Input::Ready(_))) {
+            Some(Input::Ready(ready)) => ready,
+            _ => !self.input.is_empty() || ready(&mut self.console)?,
+        };
+        self.log.record(Input::Ready(ready));
+        Ok(ready)
+    }
+}
 
-pub struct Vm {
-    memory:    [u16; MEMORY_MAX],
-    registers: [u16; REGISTERS],
+impl<C: io::Console> io::Console for Pending<C> {
+    fn getc(&mut self) -> Result<u8, io::IoError> {
+        let c = match self.log.replayed(|input| matches!(input, Input::Char(_))) {
+            Some(Input::Char(c)) => c,
+            _ => match self.input.pop_front() {
+                Some(c) => c,
+                None => self.console.getc()?,
+            },
+        };
+        self.log.record(Input::Char(c));
+        Ok(c)
+    }
+    fn putc(&mut self, c: u8) -> Result<(), io::IoError> {
+        match self.log.replay {
+            Some(_) => Ok(()),
+            None => self.console.putc(c),
+        }
+    }
+    fn puts(&mut self, buf: &[u8]) -> Result<(), io::IoError> {
+        match self.log.replay {
+            Some(_) => Ok(()),
+            None => self.console.puts(buf),
+        }
+    }
+    fn hasc(&mut self) -> Result<bool, io::IoError> {
+        self.ready(C::hasc)
+    }
+    fn poll(&mut self) -> Result<bool, io::IoError> {
+        self.ready(C::poll)
+    }
+    fn flush(&mut self) -> Result<(), io::IoError> {
+        self.console.flush()
+    }
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    BadMagic,
+    Version { found: u16, expected: u16 },
+    Truncated,
+    TrailingData { length: usize },
 }
 
 pub trait VmMem {
     fn read_reg(&self, register: Register) -> u16;
     fn write_reg(&mut self, register: Register, value: u16);
     fn read_mem(&self, address: u16) -> u16;
-    fn write_mem(&mut self, address: u16, value: u16);
+    /// fails when a character written to DDR cannot be put out
+    fn write_mem(&mut self, address: u16, value: u16) -> Result<(), io::IoError>;
+    /// memory contents without the side effects of device registers, for inspection
+    fn peek_mem(&self, address: u16) -> u16;
+    /// console behind the keyboard and display registers, also used by the i/o traps
+    fn console(&self) -> RefMut<'_, dyn io::Console + '_>;
+    /// stores into memory without the side effects of device registers, for restoring state
+    fn poke_mem(&mut self, address: u16, value: u16);
     fn c_str(&self, address: u16) -> Vec<u8>;
+    /// string with two characters packed per word (low byte first), as consumed by PUTSP
+    fn packed_str(&self, address: u16) -> Vec<u8>;
+    /// returns (vector, priority) of the device interrupt requested at the moment, if any
+    fn pending_interrupt(&self) -> Option<(u16, u16)>;
+    fn trap_dispatch(&self) -> TrapDispatch;
+    /// answers the console gave the program, recorded and replayed by the debugger
+    fn input_log(&mut self) -> &mut InputLog;
 }
 
-impl VmMem for Vm {
+impl<C: io::Console> VmMem for Vm<C> {
     fn read_reg(&self, register: Register) -> u16 {
         self.registers[register.0]
     }
@@ -26,30 +164,221 @@ impl VmMem for Vm {
     }
     fn read_mem(&self, address: u16) -> u16 {
         match address {
-            0xfe00 => match io::hasc() {
-                Ok(true) => 1u16 << 15,
-                _ => 0,
+            KBSR => match self.console().poll().unwrap_or(false) {
+                true => KBSR_READY | (self.memory[KBSR as usize] & KBSR_IE),
+                false => self.memory[KBSR as usize] & KBSR_IE,
             },
-            0xfe02 => io::getc().unwrap_or(0) as u16,
-            0xfe04 => panic!("read access to DSR is not implemented"),
-            0xfe06 => panic!("read access to DDR is not implemented"),
-            0xfffe => panic!("read access to MCR is not implemented"),
+            KBDR => self.console().getc().unwrap_or(0) as u16,
+            DSR => DSR_READY,
             _ => self.memory[address as usize],
         }
     }
-    fn write_mem(&mut self, address: u16, value: u16) {
+    fn write_mem(&mut self, address: u16, value: u16) -> Result<(), io::IoError> {
         match address {
-            0xfe00 | 0xfe02 | 0xfe04 | 0xfe06 | 0xfffe => panic!("write access to memory-mapped registers are forbidden"),
+            KBSR => self.memory[KBSR as usize] = value & KBSR_IE,
+            DDR => {
+                self.memory[DDR as usize] = value;
+                self.console().putc(value as u8)?;
+            }
+            // read-only registers, stores to them are dropped like on the hardware
+            KBDR | DSR => {}
             _ => self.memory[address as usize] = value,
         }
+        Ok(())
+    }
+    fn peek_mem(&self, address: u16) -> u16 {
+        self.memory[address as usize]
+    }
+    fn console(&self) -> RefMut<'_, dyn io::Console + '_> {
+        RefMut::map(self.console.borrow_mut(), |console| console as &mut dyn io::Console)
+    }
+    fn poke_mem(&mut self, address: u16, value: u16) {
+        self.memory[address as usize] = value;
     }
     fn c_str(&self, address: u16) -> Vec<u8> {
         self.memory[address as usize..].iter().take_while(|&&x| x != 0).map(|&x| x as u8).collect()
     }
+    fn packed_str(&self, address: u16) -> Vec<u8> {
+        self.memory[address as usize..].iter().take_while(|&&x| x != 0).flat_map(|&x| [x as u8, (x >> 8) as u8]).take_while(|&c| c != 0).collect()
+    }
+    fn pending_interrupt(&self) -> Option<(u16, u16)> {
+        if self.memory[KBSR as usize] & KBSR_IE != 0 && self.hasc() {
+            return Some((KBD_INTERRUPT_VECTOR, KBD_INTERRUPT_PRIORITY));
+        }
+        None
+    }
+    fn trap_dispatch(&self) -> TrapDispatch {
+        self.trap_dispatch
+    }
+    fn input_log(&mut self) -> &mut InputLog {
+        &mut self.console.get_mut().log
+    }
 }
 
-impl Default for Vm {
+impl<C: io::Console + Default> Default for Vm<C> {
     fn default() -> Self {
-        Self { memory: [0u16; MEMORY_MAX], registers: [0u16; REGISTERS] }
+        Self::with_console(C::default())
+    }
+}
+
+fn read_be<const N: usize>(data: &mut &[u8]) -> Result<[u8; N], SnapshotError> {
+    let (bytes, rest) = data.split_first_chunk::<N>().ok_or(SnapshotError::Truncated)?;
+    *data = rest;
+    Ok(*bytes)
+}
+
+impl<C: io::Console> Vm<C> {
+    pub fn with_console(console: C) -> Self {
+        let mut memory = [0u16; MEMORY_MAX];
+        memory[MCR as usize] = MCR_CLOCK_ENABLE;
+        Self { memory, registers: [0u16; REGISTERS], console: RefCell::new(Pending { input: VecDeque::new(), console, log: InputLog::default() }), trap_dispatch: TrapDispatch::default() }
+    }
+
+    pub fn set_trap_dispatch(&mut self, trap_dispatch: TrapDispatch) {
+        self.trap_dispatch = trap_dispatch;
+    }
+
+    pub fn console_mut(&mut self) -> &mut C {
+        &mut self.console.get_mut().console
+    }
+
+    fn hasc(&self) -> bool {
+        self.console().hasc().unwrap_or(false)
+    }
+
+    /// complete machine state: `LC3S` magic and u16 version, then all registers (including PSR and the saved stack
+    /// pointers) and all memory (including device registers) as big-endian words, then a u32 count and the bytes of
+    /// input typed but not read yet. input still waiting in the console is moved into the vm for that
+    pub fn snapshot(&self) -> Result<Vec<u8>, io::IoError> {
+        let mut pending = self.console.borrow_mut();
+        while pending.console.hasc()? {
+            let c = pending.console.getc()?;
+            pending.input.push_back(c);
+        }
+        let input = &pending.input;
+        let mut data = Vec::with_capacity(4 + 2 + 2 * (REGISTERS + MEMORY_MAX) + 4 + input.len());
+        data.extend_from_slice(SNAPSHOT_MAGIC);
+        data.extend_from_slice(&SNAPSHOT_VERSION.to_be_bytes());
+        data.extend(self.registers.iter().chain(self.memory.iter()).flat_map(|w| w.to_be_bytes()));
+        data.extend_from_slice(&(input.len() as u32).to_be_bytes());
+        data.extend(input.iter());
+        Ok(data)
+    }
+
+    /// rebuilds a machine from the output of `snapshot`, attached to `console`
+    pub fn restore(mut data: &[u8], console: C) -> Result<Self, SnapshotError> {
+        if read_be::<4>(&mut data)? != *SNAPSHOT_MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = u16::from_be_bytes(read_be(&mut data)?);
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::Version { found: version, expected: SNAPSHOT_VERSION });
+        }
+        let mut vm = Self::with_console(console);
+        for word in vm.registers.iter_mut().chain(vm.memory.iter_mut()) {
+            *word = u16::from_be_bytes(read_be(&mut data)?);
+        }
+        let length = u32::from_be_bytes(read_be(&mut data)?) as usize;
+        if data.len() < length {
+            return Err(SnapshotError::Truncated);
+        }
+        vm.console.get_mut().input.extend(&data[..length]);
+        if data.len() > length {
+            return Err(SnapshotError::TrailingData { length: data.len() - length });
+        }
+        Ok(vm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm_spec::{Object, TickError, VmSpec};
+
+    // console whose display is gone, e.g. a closed pipe
+    struct Closed;
+
+    impl io::Console for Closed {
+        fn getc(&mut self) -> Result<u8, io::IoError> {
+            Err(io::IoError(std::io::ErrorKind::UnexpectedEof.into()))
+        }
+        fn puts(&mut self, _: &[u8]) -> Result<(), io::IoError> {
+            Err(io::IoError(std::io::ErrorKind::BrokenPipe.into()))
+        }
+        fn hasc(&mut self) -> Result<bool, io::IoError> {
+            Ok(false)
+        }
+    }
+
+    #[test]
+    fn ignores_writes_to_read_only_registers() {
+        let mut vm: Vm<io::Buffers> = Vm::default();
+        vm.console_mut().input.extend(b"a");
+        vm.write_mem(KBDR, 0x1234).unwrap();
+        vm.write_mem(DSR, 0x1234).unwrap();
+        assert_eq!(vm.read_mem(DSR), DSR_READY);
+        assert_eq!(vm.read_mem(KBDR), b'a' as u16);
+        assert_eq!(vm.peek_mem(KBDR), 0);
+        assert_eq!(vm.peek_mem(DSR), 0);
+    }
+
+    #[test]
+    fn writes_ddr_to_the_console() {
+        let mut vm: Vm<io::Buffers> = Vm::default();
+        vm.write_mem(DDR, b'x' as u16).unwrap();
+        assert_eq!(vm.console_mut().output, b"x");
+        vm.poke_mem(DDR, b'y' as u16);
+        assert_eq!(vm.console_mut().output, b"x");
+    }
+
+    #[test]
+    fn reports_display_errors() {
+        let mut vm = Vm::with_console(Closed);
+        assert!(vm.write_mem(DDR, b'x' as u16).is_err());
+        // LD R0, #2; STI R0, #2; HALT; 'x'; DDR
+        let program = [0x3000, 0x2002, 0xb002, 0xf025, b'x' as u16, DDR];
+        vm.load_obj(&Object { name: "test", obj: &program }).unwrap();
+        vm.write_reg(crate::vm_spec::R_PC, 0x3000);
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert!(matches!(vm.tick(), Err(TickError::Io(_))));
+    }
+
+    #[test]
+    fn restores_halted_snapshots() {
+        // GETC; OUT; HALT
+        let mut vm: Vm<io::Buffers> = VmSpec::load(&[Object { name: "test", obj: &[0x3000, 0xf020, 0xf021, 0xf025] }]).unwrap();
+        vm.console_mut().input.extend(b"ab");
+        assert!(matches!(crate::vm_spec::run(&mut vm), Ok(())));
+        let snapshot = vm.snapshot().unwrap();
+
+        let mut restored = Vm::restore(&snapshot, io::Buffers::default()).unwrap();
+        assert_eq!(restored.registers, vm.registers);
+        assert!(restored.memory == vm.memory);
+        assert!(matches!(restored.tick(), Ok(false)));
+        assert!(restored.console_mut().output.is_empty());
+        assert_eq!(restored.console().getc().unwrap(), b'b');
+
+        // an os halts by stopping the clock, which no instruction runs without
+        restored.poke_mem(MCR, 0);
+        restored.write_reg(crate::vm_spec::R_PC, 0x3000);
+        assert!(matches!(restored.tick(), Ok(false)));
+        assert_eq!(restored.read_reg(crate::vm_spec::R_PC), 0x3000);
+    }
+
+    #[test]
+    fn rejects_broken_snapshots() {
+        let snapshot = Vm::<io::Buffers>::default().snapshot().unwrap();
+        let restore = |data: &[u8]| Vm::restore(data, io::Buffers::default()).map(|_| ());
+        assert!(restore(&snapshot).is_ok());
+
+        assert!(matches!(restore(b"LC3X\x00\x01"), Err(SnapshotError::BadMagic)));
+        let mut newer = snapshot.clone();
+        newer[5] += 1;
+        assert!(matches!(restore(&newer), Err(SnapshotError::Version { found, expected: SNAPSHOT_VERSION }) if found == SNAPSHOT_VERSION + 1));
+        assert!(matches!(restore(&snapshot[..snapshot.len() - 1]), Err(SnapshotError::Truncated)));
+        assert!(matches!(restore(&snapshot[..100]), Err(SnapshotError::Truncated)));
+        let mut longer = snapshot.clone();
+        longer.extend(b"xy");
+        assert!(matches!(restore(&longer), Err(SnapshotError::TrailingData { length: 2 })));
     }
 }
@@ -4,26 +4,86 @@ use crate::ops_parse;
 use crate::vm;
 
 const R0: Register = Register(0);
+const R6: Register = Register(6);
 const R7: Register = Register(7);
-const R_PC: Register = Register(8);
-const R_COND: Register = Register(9);
-const R_PC_INIT: u16 = 0x3000;
+pub const R_PC: Register = Register(8);
+pub const R_PSR: Register = Register(9);
+const R_SAVED_SSP: Register = Register(10);
+const R_SAVED_USP: Register = Register(11);
 
 const COND_P: u16 = 1 << 0u16;
 const COND_Z: u16 = 1 << 1u16;
 const COND_N: u16 = 1 << 2u16;
 
+const PSR_COND: u16 = COND_N | COND_Z | COND_P;
+const PSR_PRIORITY: u16 = 0b111 << 8u16;
+const PSR_USER: u16 = 1 << 15u16;
+
+const VECTOR_TABLE: u16 = 0x0100;
+const EXCEPTION_PRIVILEGE: u16 = 0x00;
+const EXCEPTION_ILLEGAL_OPCODE: u16 = 0x01;
+const EXCEPTION_ACV: u16 = 0x02;
+
+// the supervisor stack grows down from the start of user space, through the top of system space
+const SUPERVISOR_STACK: u16 = 0x3000;
+
+const USER_SPACE_START: u16 = 0x3000;
+const USER_SPACE_END: u16 = DEVICE_SPACE_START;
+const DEVICE_SPACE_START: u16 = vm::KBSR;
+
+#[derive(Debug)]
 pub enum TickError {
     Io(io::IoError),
     Parse(ops_parse::ParseError),
+    UnhandledException { vector: u16, pc: u16 },
+    UnhandledTrap { trap_vector: u16, pc: u16 },
+    StackOverflow { sp: u16 },
+    Interrupted,
 }
 
+#[derive(Debug)]
 pub enum LoadError {
     EmptyProgram,
+    Overlap { first: String, second: String, address: u16 },
+    AddressOverflow { name: String, origin: u16, length: usize, address: u32 },
+    DeviceRegion { name: String, origin: u16, length: usize, address: u16 },
+}
+
+/// named object image: origin followed by the words placed at it
+pub struct Object<'a> {
+    pub name: &'a str,
+    pub obj:  &'a [u16],
+}
+
+impl Object<'_> {
+    // addresses occupied by the object, computed in u32 so that the end of the address space is representable
+    fn span(&self) -> std::ops::Range<u32> {
+        let origin = self.obj[0] as u32;
+        origin..origin + self.obj.len() as u32 - 1
+    }
+    fn validate(&self) -> Result<(), LoadError> {
+        if self.obj.is_empty() {
+            return Err(LoadError::EmptyProgram);
+        }
+        let (origin, length, span) = (self.obj[0], self.obj.len() - 1, self.span());
+        if span.end > vm::MEMORY_MAX as u32 {
+            // where the last word would have to go
+            return Err(LoadError::AddressOverflow { name: self.name.to_string(), origin, length, address: span.end - 1 });
+        }
+        if span.end > DEVICE_SPACE_START as u32 {
+            let address = span.start.max(DEVICE_SPACE_START as u32) as u16;
+            return Err(LoadError::DeviceRegion { name: self.name.to_string(), origin, length, address });
+        }
+        Ok(())
+    }
 }
 
+/// runs until the program halts; fails with `TickError::Interrupted` between instructions once SIGINT arrived
 pub fn run(vm: &mut impl VmSpec) -> Result<(), TickError> {
     loop {
+        if io::take_interrupt() {
+            return Err(TickError::Interrupted);
+        }
         match vm.tick() {
             Ok(true) => continue,
             Ok(false) => return Ok(()),
@@ -33,54 +93,188 @@ pub fn run(vm: &mut impl VmSpec) -> Result<(), TickError> {
 }
 
 pub trait VmSpec where Self: Sized {
-    fn load(obj: &[u16]) -> Result<Self, LoadError>;
+    fn load(objs: &[Object]) -> Result<Self, LoadError> where Self: Default;
+    fn load_obj(&mut self, obj: &Object) -> Result<(), LoadError>;
     fn tick(&mut self) -> Result<bool, TickError>; 
-    fn tick_op(&mut self, op: Operation) -> Result<bool, io::IoError>;
-    fn trap(&mut self, trap_vector: u16) -> Result<bool, io::IoError>;
+    fn tick_op(&mut self, op: Operation) -> Result<bool, TickError>;
+    fn trap(&mut self, trap_vector: u16) -> Result<bool, TickError>;
+    fn interrupt(&mut self, vector: u16, priority: u16) -> Result<(), TickError>;
+    fn exception(&mut self, vector: u16) -> Result<bool, TickError>;
 }
 
 fn set_cond_reg(vm_mem: &mut impl vm::VmMem, register: Register) {
     let value = vm_mem.read_reg(register);
-    if value == 0 {
-        vm_mem.write_reg(R_COND, COND_Z);
+    let cond = if value == 0 {
+        COND_Z
     } else if value < 1 << 15 {
-        vm_mem.write_reg(R_COND, COND_P);
+        COND_P
     } else {
-        vm_mem.write_reg(R_COND, COND_N);
+        COND_N
+    };
+    vm_mem.write_reg(R_PSR, (vm_mem.read_reg(R_PSR) & !PSR_COND) | cond);
+}
+
+fn push(vm_mem: &mut impl vm::VmMem, value: u16) -> Result<(), TickError> {
+    let sp = vm_mem.read_reg(R6).wrapping_sub(1);
+    // a stack reaching into the device registers would overwrite MCR and stop the clock
+    if sp >= DEVICE_SPACE_START {
+        return Err(TickError::StackOverflow { sp });
+    }
+    vm_mem.write_reg(R6, sp);
+    vm_mem.write_mem(sp, value).map_err(TickError::Io)
+}
+
+fn pop(vm_mem: &mut impl vm::VmMem) -> u16 {
+    let sp = vm_mem.read_reg(R6);
+    vm_mem.write_reg(R6, sp.wrapping_add(1));
+    vm_mem.read_mem(sp)
+}
+
+fn access_violation(vm_mem: &impl vm::VmMem, address: u16) -> bool {
+    vm_mem.read_reg(R_PSR) & PSR_USER != 0 && !(USER_SPACE_START..USER_SPACE_END).contains(&address)
+}
+
+// saves PSR and PC on the supervisor stack and jumps to `handler`, which returns with RTI; used by exceptions, interrupts
+// and (with `TrapDispatch::Supervisor`) trap routines
+fn enter_supervisor(vm_mem: &mut impl vm::VmMem, handler: u16, psr: u16) -> Result<(), TickError> {
+    let old_psr = vm_mem.read_reg(R_PSR);
+    if old_psr & PSR_USER != 0 {
+        vm_mem.write_reg(R_SAVED_USP, vm_mem.read_reg(R6));
+        vm_mem.write_reg(R6, vm_mem.read_reg(R_SAVED_SSP));
+    }
+    push(vm_mem, old_psr)?;
+    push(vm_mem, vm_mem.read_reg(R_PC))?;
+    vm_mem.write_reg(R_PSR, psr & !PSR_USER);
+    vm_mem.write_reg(R_PC, handler);
+    Ok(())
+}
+
+// traps served by the vm itself when the trap vector table has no routine
+fn builtin_trap(vm_mem: &mut impl vm::VmMem, trap_vector: u16) -> Result<bool, io::IoError> {
+    match trap_vector {
+        0x20 /* getc */ => {
+            let c = vm_mem.console().getc()?;
+            vm_mem.write_reg(R0, c as u16);
+        }
+        0x21 /* out */ => vm_mem.console().putc(vm_mem.read_reg(R0) as u8)?,
+        0x22 /* puts */ => vm_mem.console().puts(&vm_mem.c_str(vm_mem.read_reg(R0)))?,
+        0x23 /* in */ => {
+            vm_mem.console().puts(b"Enter a character: ")?;
+            let c = vm_mem.console().getc()?;
+            vm_mem.console().putc(c)?;
+            vm_mem.write_reg(R0, c as u16);
+        }
+        0x24 /* putsp */ => vm_mem.console().puts(&vm_mem.packed_str(vm_mem.read_reg(R0)))?,
+        0x25 /* halt */ => {
+            // the pc stays on the HALT, so that a snapshot of the halted machine halts again once restored
+            vm_mem.write_reg(R_PC, vm_mem.read_reg(R_PC).wrapping_sub(1));
+            return Ok(false);
+        }
+        _ => unreachable!("not a built-in trap vector: {:#x}", trap_vector),
     }
+    Ok(true)
 }
 
-impl<T: vm::VmMem+Default> VmSpec for T {
-    fn load(obj: &[u16]) -> Result<T, LoadError> {
-        if obj.is_empty() {
+impl<T: vm::VmMem> VmSpec for T {
+    fn load(objs: &[Object]) -> Result<T, LoadError> where T: Default {
+        if objs.is_empty() {
             return Err(LoadError::EmptyProgram);
         }
-        let origin = obj[0];
+        for o in objs {
+            o.validate()?;
+        }
+        for (i, first) in objs.iter().enumerate() {
+            for second in &objs[i + 1..] {
+                let (a, b) = (first.span(), second.span());
+                if a.start < b.end && b.start < a.end {
+                    return Err(LoadError::Overlap { first: first.name.to_string(), second: second.name.to_string(), address: a.start.max(b.start) as u16 });
+                }
+            }
+        }
         let mut vm = T::default();
-        for (i, &value) in obj[1..].iter().enumerate() {
-            vm.write_mem(origin + i as u16, value);
+        for o in objs {
+            vm.load_obj(o)?;
         }
-        vm.write_reg(R_PC, R_PC_INIT);
-        vm.write_reg(R_COND, COND_Z);
+        vm.write_reg(R_PC, objs[0].obj[0]);
+        vm.write_reg(R_PSR, COND_Z);
+        // programs start in supervisor mode, so R6 already is the supervisor stack pointer
+        vm.write_reg(R6, SUPERVISOR_STACK);
+        vm.write_reg(R_SAVED_SSP, SUPERVISOR_STACK);
         Ok(vm)
     }
-    fn trap(&mut self, trap_vector: u16) -> Result<bool, io::IoError> {
+    fn load_obj(&mut self, obj: &Object) -> Result<(), LoadError> {
+        obj.validate()?;
+        let origin = obj.obj[0];
+        for (i, &value) in obj.obj[1..].iter().enumerate() {
+            self.poke_mem(origin + i as u16, value);
+        }
+        Ok(())
+    }
+    fn trap(&mut self, trap_vector: u16) -> Result<bool, TickError> {
+        // routines installed in the trap vector table (e.g. by an os image) take precedence over the built-in ones; R7
+        // already holds the return address
+        let routine = self.read_mem(trap_vector);
+        if routine != 0 {
+            match self.trap_dispatch() {
+                vm::TrapDispatch::Subroutine => self.write_reg(R_PC, routine),
+                vm::TrapDispatch::Supervisor => enter_supervisor(self, routine, self.read_reg(R_PSR))?,
+            }
+            return Ok(true);
+        }
         match trap_vector {
-            0x20 /* getc */ => self.write_reg(R0, io::getc()? as u16),
-            0x21 /* out */ => io::putc(self.read_reg(R0) as u8)?,
-            0x22 /* puts */ => io::puts(&self.c_str(self.read_reg(R0)))?,
-            0x25 /* halt */ => return Ok(false),
-            _ => panic!("not implemented trap vector: {:#x}", trap_vector)
+            0x20..=0x25 => builtin_trap(self, trap_vector).map_err(TickError::Io),
+            _ => Err(TickError::UnhandledTrap { trap_vector, pc: self.read_reg(R_PC).wrapping_sub(1) }),
         }
+    }
+    fn interrupt(&mut self, vector: u16, priority: u16) -> Result<(), TickError> {
+        let psr = self.read_reg(R_PSR);
+        // like exceptions, interrupts without a handler stop the vm instead of jumping to 0x0000
+        let handler = self.read_mem(VECTOR_TABLE.wrapping_add(vector));
+        if handler == 0 {
+            return Err(TickError::UnhandledException { vector, pc: self.read_reg(R_PC) });
+        }
+        enter_supervisor(self, handler, ((priority << 8) & PSR_PRIORITY) | (psr & PSR_COND))
+    }
+    fn exception(&mut self, vector: u16) -> Result<bool, TickError> {
+        // without a handler in the vector table there is nothing to recover with, so stop the vm instead of jumping to 0x0000
+        let handler = self.read_mem(VECTOR_TABLE.wrapping_add(vector));
+        if handler == 0 {
+            return Err(TickError::UnhandledException { vector, pc: self.read_reg(R_PC).wrapping_sub(1) });
+        }
+        enter_supervisor(self, handler, self.read_reg(R_PSR))?;
         Ok(true)
     }
     fn tick(&mut self) -> Result<bool, TickError> {
+        // the clock of a machine halted by an os image stays off, e.g. when it is restored from a snapshot
+        if self.peek_mem(vm::MCR) & vm::MCR_CLOCK_ENABLE == 0 {
+            return Ok(false);
+        }
+        if let Some((vector, priority)) = self.pending_interrupt() {
+            if priority > (self.read_reg(R_PSR) & PSR_PRIORITY) >> 8 {
+                self.interrupt(vector, priority)?;
+            }
+        }
         let pc = self.read_reg(R_PC);
-        let op = Operation::parse(self.read_mem(pc)).map_err(TickError::Parse)?;
+        if access_violation(self, pc) {
+            self.write_reg(R_PC, pc.wrapping_add(1));
+            return self.exception(EXCEPTION_ACV);
+        }
+        // fetches are not data reads: they neither touch device registers nor count as watched reads
+        let parsed = Operation::parse(self.peek_mem(pc));
         self.write_reg(R_PC, pc.wrapping_add(1));
-        self.tick_op(op).map_err(TickError::Io)
+        let running = match parsed {
+            Ok(op) => self.tick_op(op)?,
+            Err(ops_parse::ParseError::IllegalOpcode { .. }) => self.exception(EXCEPTION_ILLEGAL_OPCODE)?,
+            Err(e) => return Err(TickError::Parse(e)),
+        };
+        // os images halt the machine by clearing the clock enable bit of MCR
+        let running = running && self.peek_mem(vm::MCR) & vm::MCR_CLOCK_ENABLE != 0;
+        if !running {
+            self.console().flush().map_err(TickError::Io)?;
+        }
+        Ok(running)
     }
-    fn tick_op(&mut self, op: Operation) -> Result<bool, io::IoError> {
+    fn tick_op(&mut self, op: Operation) -> Result<bool, TickError> {
         match op {
             Operation::Add { dr, sr1, arg: Argument::Register(sr2) } => {
                 self.write_reg(dr, self.read_reg(sr1).wrapping_add(self.read_reg(sr2)));
@@ -99,7 +293,7 @@ impl<T: vm::VmMem+Default> VmSpec for T {
                 set_cond_reg(self, dr);
             }
             Operation::Br { n, z, p, pc_offset } => {
-                let cond = self.read_reg(R_COND);
+                let cond = self.read_reg(R_PSR) & PSR_COND;
                 if n && (COND_N & cond) != 0 || z && (COND_Z & cond) != 0 || p && (COND_P & cond) != 0 {
                     self.write_reg(R_PC, self.read_reg(R_PC).wrapping_add(pc_offset));
                 }
@@ -116,16 +310,31 @@ impl<T: vm::VmMem+Default> VmSpec for T {
                 self.write_reg(R_PC, self.read_reg(base_r));
             }
             Operation::Ld { dr, pc_offset } => {
-                self.write_reg(dr, self.read_mem(self.read_reg(R_PC).wrapping_add(pc_offset)));
+                let address = self.read_reg(R_PC).wrapping_add(pc_offset);
+                if access_violation(self, address) {
+                    return self.exception(EXCEPTION_ACV);
+                }
+                self.write_reg(dr, self.read_mem(address));
                 set_cond_reg(self, dr);
             }
             Operation::Ldi { dr, pc_offset } => {
-                let address = self.read_mem(self.read_reg(R_PC).wrapping_add(pc_offset));
+                let pointer = self.read_reg(R_PC).wrapping_add(pc_offset);
+                if access_violation(self, pointer) {
+                    return self.exception(EXCEPTION_ACV);
+                }
+                let address = self.read_mem(pointer);
+                if access_violation(self, address) {
+                    return self.exception(EXCEPTION_ACV);
+                }
                 self.write_reg(dr, self.read_mem(address));
                 set_cond_reg(self, dr);
             }
             Operation::Ldr { dr, base_r, offset } => {
-                self.write_reg(dr, self.read_mem(self.read_reg(base_r).wrapping_add(offset)));
+                let address = self.read_reg(base_r).wrapping_add(offset);
+                if access_violation(self, address) {
+                    return self.exception(EXCEPTION_ACV);
+                }
+                self.write_reg(dr, self.read_mem(address));
                 set_cond_reg(self, dr);
             }
             Operation::Lea { dr, pc_offset } => {
@@ -136,22 +345,303 @@ impl<T: vm::VmMem+Default> VmSpec for T {
                 self.write_reg(dr, !self.read_reg(sr));
                 set_cond_reg(self, dr);
             }
-            Operation::Rti => panic!("rti operation is not implemented"),
+            Operation::Rti => {
+                if self.read_reg(R_PSR) & PSR_USER != 0 {
+                    return self.exception(EXCEPTION_PRIVILEGE);
+                }
+                let pc = pop(self);
+                let psr = pop(self);
+                self.write_reg(R_PC, pc);
+                self.write_reg(R_PSR, psr & (PSR_USER | PSR_PRIORITY | PSR_COND));
+                if psr & PSR_USER != 0 {
+                    self.write_reg(R_SAVED_SSP, self.read_reg(R6));
+                    self.write_reg(R6, self.read_reg(R_SAVED_USP));
+                }
+            }
             Operation::St { sr, pc_offset } => {
-                self.write_mem(self.read_reg(R_PC).wrapping_add(pc_offset), self.read_reg(sr));
+                let address = self.read_reg(R_PC).wrapping_add(pc_offset);
+                if access_violation(self, address) {
+                    return self.exception(EXCEPTION_ACV);
+                }
+                self.write_mem(address, self.read_reg(sr)).map_err(TickError::Io)?;
             }
             Operation::Sti { sr, pc_offset } => {
-                let address = self.read_mem(self.read_reg(R_PC).wrapping_add(pc_offset));
-                self.write_mem(address, self.read_reg(sr));
+                let pointer = self.read_reg(R_PC).wrapping_add(pc_offset);
+                if access_violation(self, pointer) {
+                    return self.exception(EXCEPTION_ACV);
+                }
+                let address = self.read_mem(pointer);
+                if access_violation(self, address) {
+                    return self.exception(EXCEPTION_ACV);
+                }
+                self.write_mem(address, self.read_reg(sr)).map_err(TickError::Io)?;
             }
             Operation::Str { sr, base_r, offset } => {
-                self.write_mem(self.read_reg(base_r).wrapping_add(offset), self.read_reg(sr));
+                let address = self.read_reg(base_r).wrapping_add(offset);
+                if access_violation(self, address) {
+                    return self.exception(EXCEPTION_ACV);
+                }
+                self.write_mem(address, self.read_reg(sr)).map_err(TickError::Io)?;
             }
             Operation::Trap { trap_vector } => {
-                self.write_reg(R7, self.read_reg(R_PC));
-                return self.trap(trap_vector);
+                let (pc, r7) = (self.read_reg(R_PC), self.read_reg(R7));
+                self.write_reg(R7, pc);
+                return match self.trap(trap_vector) {
+                    // a signal cut a read short: undo the trap, it starts over when the vm goes on
+                    Err(TickError::Io(e)) if e.0.kind() == std::io::ErrorKind::Interrupted => {
+                        self.write_reg(R7, r7);
+                        self.write_reg(R_PC, pc.wrapping_sub(1));
+                        Ok(true)
+                    }
+                    result => result,
+                };
             }
         }
         Ok(true)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io;
+    use crate::vm::{Vm, VmMem};
+
+    fn user_vm(program: &[u16]) -> Vm<io::Buffers> {
+        let mut vm: Vm<io::Buffers> = VmSpec::load(&[Object { name: "test", obj: program }]).unwrap();
+        vm.write_reg(R_PSR, PSR_USER | COND_Z);
+        vm.write_reg(R6, 0xfdff);
+        vm.write_reg(R_SAVED_SSP, 0x3000);
+        vm
+    }
+
+    #[test]
+    fn trap_routines_run_in_supervisor_mode() {
+        // TRAP x25; ADD R2, R2, #1 with a routine at x0200: ADD R1, R1, #5; RTI
+        let mut vm = user_vm(&[0x3000, 0xf025, 0x14a1]);
+        vm.set_trap_dispatch(vm::TrapDispatch::Supervisor);
+        vm.poke_mem(0x0025, 0x0200);
+        vm.poke_mem(0x0200, 0x1265);
+        vm.poke_mem(0x0201, 0x8000);
+
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert_eq!(vm.read_reg(R_PC), 0x0200);
+        assert_eq!(vm.read_reg(R_PSR), COND_Z);
+        assert_eq!(vm.read_reg(R6), 0x2ffe);
+        assert_eq!(vm.read_reg(R_SAVED_USP), 0xfdff);
+        assert_eq!(vm.peek_mem(0x2fff), PSR_USER | COND_Z);
+        assert_eq!(vm.peek_mem(0x2ffe), 0x3001);
+
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert_eq!(vm.read_reg(Register(1)), 5);
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert_eq!(vm.read_reg(R_PC), 0x3001);
+        assert_eq!(vm.read_reg(R_PSR), PSR_USER | COND_Z);
+        assert_eq!(vm.read_reg(R6), 0xfdff);
+        assert_eq!(vm.read_reg(R_SAVED_SSP), 0x3000);
+
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert_eq!(vm.read_reg(Register(2)), 1);
+    }
+
+    #[test]
+    fn trap_routines_return_with_ret() {
+        // TRAP x25; ADD R2, R2, #1 with a routine at x0200 as in lc3os.obj: ADD R1, R1, #5; RET
+        let mut vm: Vm<io::Buffers> = VmSpec::load(&[Object { name: "test", obj: &[0x3000, 0xf025, 0x14a1] }, Object { name: "os", obj: &[0x0200, 0x1265, 0xc1c0] }]).unwrap();
+        vm.poke_mem(0x0025, 0x0200);
+
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert_eq!(vm.read_reg(R_PC), 0x0200);
+        assert_eq!(vm.read_reg(R7), 0x3001);
+        assert_eq!(vm.read_reg(R6), 0x3000);
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert_eq!(vm.read_reg(R_PC), 0x3001);
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert_eq!(vm.read_reg(Register(1)), 5);
+        assert_eq!(vm.read_reg(Register(2)), 1);
+    }
+
+    #[test]
+    fn loaded_programs_have_a_supervisor_stack() {
+        // TRAP x25; ADD R2, R2, #1 with a routine at x0200: RTI, called without setting up R6 first
+        let mut vm: Vm<io::Buffers> = VmSpec::load(&[Object { name: "os", obj: &[0x0200, 0x8000] }, Object { name: "test", obj: &[0x3000, 0xf025, 0x14a1] }]).unwrap();
+        vm.write_reg(R_PC, 0x3000);
+        vm.set_trap_dispatch(vm::TrapDispatch::Supervisor);
+        vm.poke_mem(0x0025, 0x0200);
+
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert_eq!(vm.read_reg(R6), 0x2ffe);
+        assert_eq!(vm.peek_mem(0x2ffe), 0x3001);
+        assert_eq!(vm.peek_mem(vm::MCR) & vm::MCR_CLOCK_ENABLE, vm::MCR_CLOCK_ENABLE);
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert_eq!(vm.read_reg(Register(2)), 1);
+        assert_eq!(vm.read_reg(R6), 0x3000);
+    }
+
+    #[test]
+    fn pushes_stay_out_of_the_device_registers() {
+        // RTI in user mode raises a privilege mode violation, whose handler needs the supervisor stack
+        let mut vm = user_vm(&[0x3000, 0x8000]);
+        vm.poke_mem(VECTOR_TABLE + EXCEPTION_PRIVILEGE, 0x0200);
+        vm.write_reg(R_SAVED_SSP, 0);
+        assert!(matches!(vm.tick(), Err(TickError::StackOverflow { sp: 0xffff })));
+        assert_eq!(vm.peek_mem(vm::MCR) & vm::MCR_CLOCK_ENABLE, vm::MCR_CLOCK_ENABLE);
+    }
+
+    #[test]
+    fn builtin_traps_stay_in_user_mode() {
+        // LEA R0, #2; PUTS; HALT; "hi"
+        let mut vm = user_vm(&[0x3000, 0xe002, 0xf022, 0xf025, b'h' as u16, b'i' as u16, 0]);
+        assert!(matches!(run(&mut vm), Ok(())));
+        assert_eq!(vm.console_mut().output, b"hi");
+        assert_eq!(vm.read_reg(R_PSR) & PSR_USER, PSR_USER);
+        assert_eq!(vm.read_reg(R7), 0x3003);
+    }
+
+    // program that enables keyboard interrupts: LD R1, #2; STI R1, #2; ADD R2, R2, #1; x4000; xFE00, with a handler
+    // at x0200: LDI R0, #2; ADD R3, R3, #1; RTI; xFE02
+    fn interrupt_vm(keys: &[u8]) -> Vm<io::Buffers> {
+        let mut vm: Vm<io::Buffers> = VmSpec::load(&[Object { name: "test", obj: &[0x3000, 0x2202, 0xb202, 0x14a1, 0x4000, 0xfe00] }]).unwrap();
+        for (i, &word) in [0xa002, 0x16e1, 0x8000, 0xfe02].iter().enumerate() {
+            vm.poke_mem(0x0200 + i as u16, word);
+        }
+        vm.poke_mem(VECTOR_TABLE + vm::KBD_INTERRUPT_VECTOR, 0x0200);
+        vm.console_mut().input.extend(keys);
+        vm
+    }
+
+    #[test]
+    fn delivers_keyboard_interrupts() {
+        let mut vm = interrupt_vm(b"k");
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert_eq!(vm.read_reg(R_PC), 0x3002);
+
+        // the interrupt is taken before the next instruction, which runs once the handler returned
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert_eq!(vm.read_reg(R_PC), 0x0201);
+        assert_eq!(vm.read_reg(R_PSR) & PSR_PRIORITY, vm::KBD_INTERRUPT_PRIORITY << 8);
+        assert_eq!(vm.read_reg(R0), b'k' as u16);
+        assert_eq!(vm.peek_mem(0x2ffe), 0x3002);
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert_eq!(vm.read_reg(R_PC), 0x3002);
+        assert_eq!(vm.read_reg(R_PSR) & PSR_PRIORITY, 0);
+        assert_eq!(vm.read_reg(R6), 0x3000);
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert_eq!(vm.read_reg(Register(2)), 1);
+        assert_eq!(vm.read_reg(Register(3)), 1);
+    }
+
+    #[test]
+    fn masks_interrupts_of_lower_priority() {
+        let mut vm = interrupt_vm(b"k");
+        vm.write_reg(R_PSR, (vm::KBD_INTERRUPT_PRIORITY << 8) | COND_Z);
+        for _ in 0..3 {
+            assert!(matches!(vm.tick(), Ok(true)));
+        }
+        assert_eq!(vm.read_reg(R_PC), 0x3003);
+        assert_eq!(vm.read_reg(Register(2)), 1);
+        assert_eq!(vm.read_reg(Register(3)), 0);
+    }
+
+    #[test]
+    fn stops_at_interrupts_without_a_handler() {
+        let mut vm = interrupt_vm(b"k");
+        vm.poke_mem(VECTOR_TABLE + vm::KBD_INTERRUPT_VECTOR, 0);
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert!(matches!(vm.tick(), Err(TickError::UnhandledException { vector: vm::KBD_INTERRUPT_VECTOR, pc: 0x3002 })));
+    }
+
+    #[test]
+    fn rejects_objects_outside_memory() {
+        let load = |obj: &[u16]| <Vm<io::Buffers> as VmSpec>::load(&[Object { name: "test", obj }]).map(|_| ());
+        assert!(load(&[0xfdfe, 1, 2]).is_ok());
+        assert!(matches!(load(&[0xfffe, 1, 2, 3, 4]), Err(LoadError::AddressOverflow { origin: 0xfffe, length: 4, address: 0x10001, .. })));
+        assert!(matches!(load(&[0xfdff, 1, 2, 3]), Err(LoadError::DeviceRegion { origin: 0xfdff, length: 3, address: 0xfe00, .. })));
+        assert!(matches!(load(&[0xfe06, 1]), Err(LoadError::DeviceRegion { address: 0xfe06, .. })));
+    }
+
+    #[test]
+    fn starts_at_the_first_object() {
+        let objs = [Object { name: "program", obj: &[0x4000, 0xf025] }, Object { name: "os", obj: &[0x0200, 0x8000] }];
+        let vm: Vm<io::Buffers> = VmSpec::load(&objs).unwrap();
+        assert_eq!(vm.read_reg(R_PC), 0x4000);
+        assert_eq!((vm.peek_mem(0x4000), vm.peek_mem(0x0200)), (0xf025, 0x8000));
+    }
+
+    #[test]
+    fn rejects_overlapping_objects() {
+        let objs = [Object { name: "first", obj: &[0x3000, 1, 2, 3] }, Object { name: "second", obj: &[0x3002, 4] }];
+        let result = <Vm<io::Buffers> as VmSpec>::load(&objs).map(|_| ());
+        assert!(matches!(result, Err(LoadError::Overlap { first, second, address: 0x3002 }) if first == "first" && second == "second"));
+        let adjacent = [Object { name: "first", obj: &[0x3000, 1, 2, 3] }, Object { name: "second", obj: &[0x3003, 4] }];
+        assert!(<Vm<io::Buffers> as VmSpec>::load(&adjacent).is_ok());
+    }
+
+    #[test]
+    fn raises_privilege_and_illegal_opcode_exceptions() {
+        // RTI in user mode, then the reserved opcode 1101, with handlers at x0200 and x0300
+        let mut vm = user_vm(&[0x3000, 0x8000, 0xd000]);
+        vm.poke_mem(VECTOR_TABLE + EXCEPTION_PRIVILEGE, 0x0200);
+        vm.poke_mem(VECTOR_TABLE + EXCEPTION_ILLEGAL_OPCODE, 0x0300);
+        vm.poke_mem(0x0200, 0x8000);
+        vm.poke_mem(0x0300, 0x8000);
+
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert_eq!(vm.read_reg(R_PC), 0x0200);
+        assert_eq!(vm.read_reg(R_PSR) & PSR_USER, 0);
+        assert_eq!(vm.peek_mem(0x2ffe), 0x3001);
+        // the handler's RTI goes on behind the faulting instruction
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert_eq!(vm.read_reg(R_PC), 0x3001);
+        assert_eq!(vm.read_reg(R_PSR) & PSR_USER, PSR_USER);
+
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert_eq!(vm.read_reg(R_PC), 0x0300);
+        assert_eq!(vm.peek_mem(0x2ffe), 0x3002);
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert_eq!(vm.read_reg(R_PC), 0x3002);
+
+        let mut vm = user_vm(&[0x3000, 0xd000]);
+        assert!(matches!(vm.tick(), Err(TickError::UnhandledException { vector: EXCEPTION_ILLEGAL_OPCODE, pc: 0x3000 })));
+    }
+
+    #[test]
+    fn raises_access_violations_for_system_space() {
+        // LD R0, #-3 reads x2FFE, ST R0, #-3 writes x2FFE, with a handler at x0200
+        let mut vm = user_vm(&[0x3000, 0x21fd, 0x31fd]);
+        vm.poke_mem(VECTOR_TABLE + EXCEPTION_ACV, 0x0200);
+        vm.poke_mem(0x0200, 0x8000);
+        vm.poke_mem(0x2ffe, 0x1234);
+        vm.write_reg(R0, 0x5678);
+
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert_eq!(vm.read_reg(R_PC), 0x0200);
+        assert_eq!(vm.read_reg(R0), 0x5678);
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert_eq!(vm.read_reg(R_PC), 0x0200);
+        // the handler's frame went to x2FFE itself, but not the user's R0
+        assert_eq!(vm.peek_mem(0x2ffe), 0x3002);
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert_eq!(vm.read_reg(R_PC), 0x3002);
+
+        // the same instructions run in supervisor mode
+        let mut vm = user_vm(&[0x3000, 0x21fd, 0x31fd]);
+        vm.write_reg(R_PSR, COND_Z);
+        vm.poke_mem(0x2ffe, 0x1234);
+        assert!(matches!(vm.tick(), Ok(true)));
+        assert_eq!(vm.read_reg(R0), 0x1234);
+    }
+
+    #[test]
+    fn unknown_traps_stop_the_vm() {
+        let mut vm = user_vm(&[0x3000, 0xf025, 0xf026]);
+        vm.write_reg(R_PC, 0x3001);
+        assert!(matches!(vm.tick(), Err(TickError::UnhandledTrap { trap_vector: 0x26, pc: 0x3001 })));
+    }
+}
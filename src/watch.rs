@@ -0,0 +1,189 @@
+use std::cell::{RefCell, RefMut};
+use std::ops::RangeInclusive;
+
+use crate::io;
+use crate::ops::*;
+use crate::vm::{self, VmMem};
+use crate::vm_spec::{TickError, VmSpec, R_PC};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+pub struct Watchpoint {
+    pub range: RangeInclusive<u16>,
+    pub kind:  WatchKind,
+}
+
+/// access to a watched address: `pc` and `code` identify the instruction that made it, `old` and `new` are equal for reads
+pub struct WatchHit {
+    pub pc:      u16,
+    pub code:    u16,
+    pub address: u16,
+    pub write:   bool,
+    pub old:     u16,
+    pub new:     u16,
+}
+
+pub type WatchCallback = Box<dyn FnMut(&WatchHit)>;
+
+struct Access {
+    address: u16,
+    write:   bool,
+    old:     u16,
+    new:     u16,
+}
+
+/// memory wrapper that records accesses to watched address ranges; hits are reported per instruction by `tick_watched`
+pub struct Watched<V: VmMem> {
+    pub vm:      V,
+    watchpoints: Vec<Watchpoint>,
+    accesses:    RefCell<Vec<Access>>,
+    callback:    Option<WatchCallback>,
+}
+
+impl<V: VmMem> Watched<V> {
+    pub fn new(vm: V) -> Self {
+        Self { vm, watchpoints: Vec::new(), accesses: RefCell::new(Vec::new()), callback: None }
+    }
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+    /// removes watchpoints covering `address`, returns whether there were any
+    pub fn remove_watchpoints(&mut self, address: u16) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|w| !w.range.contains(&address));
+        self.watchpoints.len() != count
+    }
+    /// callback invoked for every hit right after the instruction that caused it
+    pub fn set_callback(&mut self, callback: impl FnMut(&WatchHit) + 'static) {
+        self.callback = Some(Box::new(callback));
+    }
+    fn watched(&self, address: u16, write: bool) -> bool {
+        self.watchpoints.iter().any(|w| {
+            w.range.contains(&address)
+                && match w.kind {
+                    WatchKind::Read => !write,
+                    WatchKind::Write => write,
+                    WatchKind::Access => true,
+                }
+        })
+    }
+}
+
+impl<V: VmMem + Default> Watched<V> {
+    /// executes a single instruction like `VmSpec::tick` and returns the watchpoint hits it caused
+    pub fn tick_watched(&mut self) -> Result<(bool, Vec<WatchHit>), TickError> {
+        let pc = self.vm.read_reg(R_PC);
+        let code = self.vm.peek_mem(pc);
+        // accesses made from outside of an instruction (e.g. by a debugger) are not hits
+        self.accesses.borrow_mut().clear();
+        let running = self.tick();
+        let hits: Vec<WatchHit> = self.accesses.take().into_iter().map(|a| WatchHit { pc, code, address: a.address, write: a.write, old: a.old, new: a.new }).collect();
+        if let Some(callback) = &mut self.callback {
+            hits.iter().for_each(callback);
+        }
+        Ok((running?, hits))
+    }
+}
+
+impl<V: VmMem + Default> Default for Watched<V> {
+    fn default() -> Self {
+        Self::new(V::default())
+    }
+}
+
+impl<V: VmMem> VmMem for Watched<V> {
+    fn read_reg(&self, register: Register) -> u16 {
+        self.vm.read_reg(register)
+    }
+    fn write_reg(&mut self, register: Register, value: u16) {
+        self.vm.write_reg(register, value)
+    }
+    fn read_mem(&self, address: u16) -> u16 {
+        let value = self.vm.read_mem(address);
+        if self.watched(address, false) {
+            self.accesses.borrow_mut().push(Access { address, write: false, old: value, new: value });
+        }
+        value
+    }
+    fn write_mem(&mut self, address: u16, value: u16) -> Result<(), io::IoError> {
+        if self.watched(address, true) {
+            let old = self.vm.peek_mem(address);
+            self.accesses.borrow_mut().push(Access { address, write: true, old, new: value });
+        }
+        self.vm.write_mem(address, value)
+    }
+    fn peek_mem(&self, address: u16) -> u16 {
+        self.vm.peek_mem(address)
+    }
+    fn console(&self) -> RefMut<'_, dyn io::Console + '_> {
+        self.vm.console()
+    }
+    fn poke_mem(&mut self, address: u16, value: u16) {
+        self.vm.poke_mem(address, value)
+    }
+    fn c_str(&self, address: u16) -> Vec<u8> {
+        self.vm.c_str(address)
+    }
+    fn packed_str(&self, address: u16) -> Vec<u8> {
+        self.vm.packed_str(address)
+    }
+    fn pending_interrupt(&self) -> Option<(u16, u16)> {
+        self.vm.pending_interrupt()
+    }
+    fn trap_dispatch(&self) -> vm::TrapDispatch {
+        self.vm.trap_dispatch()
+    }
+    fn input_log(&mut self) -> &mut vm::InputLog {
+        self.vm.input_log()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{Vm, MCR};
+    use crate::vm_spec::Object;
+
+    fn watched(program: &[u16], range: RangeInclusive<u16>, kind: WatchKind) -> Watched<Vm<io::Buffers>> {
+        let mut vm = Watched::new(VmSpec::load(&[Object { name: "test", obj: program }]).unwrap());
+        vm.add_watchpoint(Watchpoint { range, kind });
+        vm
+    }
+
+    #[test]
+    fn reports_data_reads_only() {
+        // LD R0, #1; HALT; x1234
+        let mut vm = watched(&[0x3000, 0x2001, 0xf025, 0x1234], 0x3000..=0x3002, WatchKind::Read);
+        let (running, hits) = vm.tick_watched().unwrap();
+        assert!(running);
+        assert_eq!(hits.iter().map(|h| (h.pc, h.address, h.write, h.new)).collect::<Vec<_>>(), [(0x3000, 0x3002, false, 0x1234)]);
+        let (running, hits) = vm.tick_watched().unwrap();
+        assert!(!running);
+        assert!(hits.is_empty());
+    }
+
+    #[test]
+    fn ignores_the_clock_check() {
+        // ADD R0, R0, #1; HALT
+        let mut vm = watched(&[0x3000, 0x1021, 0xf025], MCR..=MCR, WatchKind::Access);
+        assert!(vm.tick_watched().unwrap().1.is_empty());
+        assert!(vm.tick_watched().unwrap().1.is_empty());
+    }
+
+    #[test]
+    fn reports_writes_with_the_replaced_value() {
+        // ST R0, #1; HALT; x0007
+        let mut vm = watched(&[0x3000, 0x3001, 0xf025, 0x0007], 0x3002..=0x3002, WatchKind::Write);
+        vm.write_reg(Register(0), 0x0042);
+        let (_, hits) = vm.tick_watched().unwrap();
+        assert_eq!(hits.iter().map(|h| (h.address, h.write, h.old, h.new)).collect::<Vec<_>>(), [(0x3002, true, 0x0007, 0x0042)]);
+    }
+}